@@ -1,29 +1,192 @@
 use fs::read_dir;
-use pgutils_migrate::Migration;
+use pgutils_migrate::{template_var_names, Migration};
 use proc_macro::TokenStream;
 use quote::quote;
-use std::{convert::TryInto, env, fs, path::Path};
+use std::{
+    convert::TryInto,
+    env, fs,
+    path::{Component, Path, PathBuf},
+};
 use syn::LitStr;
 
+const WORKSPACE_PREFIX: &str = "$workspace";
+
+/// Embeds all migrations of a directory into a `Migrator`.
+///
+/// Relative paths are resolved against the directory of the crate invoking
+/// the macro and absolute paths are used as-is. Paths starting with
+/// `$workspace/` are resolved against the root of the enclosing Cargo
+/// workspace, e.g. `embed!("$workspace/migrations")`.
+///
+/// Placeholders of the form `${NAME}` in the SQL are replaced with the value
+/// of the environment variable `NAME` at compile time, e.g. to use a schema
+/// name from `${PGUTILS_SCHEMA}`. An undefined variable is a compile error.
+/// Write `$${` for a literal `${`. Other uses of `$`, such as `$1` or
+/// `$body$`, are left alone. Checksums are computed over the substituted SQL.
+/// `Migrator::from_dir` substitutes the same way at runtime. Changing a
+/// variable triggers a rebuild, like editing a migration does.
 #[proc_macro]
 pub fn embed(input: TokenStream) -> TokenStream {
     let dir = syn::parse_macro_input!(input as LitStr);
-    let path = Path::new(&env::var("CARGO_MANIFEST_DIR").unwrap()).join(&dir.value());
 
-    parse_dir(&path.to_str().unwrap()).into()
+    let manifest_dir = match env::var("CARGO_MANIFEST_DIR") {
+        Ok(manifest_dir) => manifest_dir,
+        Err(e) => {
+            return syn::Error::new(dir.span(), format!("CARGO_MANIFEST_DIR is not set: {}", e))
+                .to_compile_error()
+                .into()
+        }
+    };
+
+    let path = match resolve_dir(Path::new(&manifest_dir), &dir.value()) {
+        Ok(path) => path,
+        Err(e) => return syn::Error::new(dir.span(), e).to_compile_error().into(),
+    };
+
+    match parse_dir(&path) {
+        Ok(ts) => ts.into(),
+        Err(e) => syn::Error::new(dir.span(), e).to_compile_error().into(),
+    }
 }
 
-fn parse_dir(path: &str) -> proc_macro2::TokenStream {
-    let mut migrations: Vec<Migration> = read_dir(path)
-        .unwrap()
-        .map(|e| e.unwrap().try_into().unwrap())
-        .collect();
+fn resolve_dir(manifest_dir: &Path, dir: &str) -> Result<PathBuf, String> {
+    let workspace_path = dir
+        .strip_prefix(WORKSPACE_PREFIX)
+        .filter(|rest| rest.is_empty() || rest.starts_with('/'));
 
-    migrations.sort_by_key(|m| m.version);
+    let path = match workspace_path {
+        Some(rest) => find_workspace_root(manifest_dir)?.join(rest.trim_start_matches('/')),
+        None if Path::new(dir).is_absolute() => PathBuf::from(dir),
+        None => manifest_dir.join(dir),
+    };
 
-    quote! {
-        pgutils::migrate::Migrator::new(
-            vec![ #(#migrations),* ]
+    Ok(normalize(&path))
+}
+
+/// Returns the nearest ancestor declaring `[workspace]`, like Cargo does.
+fn find_workspace_root(manifest_dir: &Path) -> Result<PathBuf, String> {
+    let root = manifest_dir.ancestors().find(|dir| {
+        fs::read_to_string(dir.join("Cargo.toml"))
+            .is_ok_and(|manifest| manifest.lines().any(|l| l.trim() == "[workspace]"))
+    });
+
+    root.map(Path::to_path_buf).ok_or_else(|| {
+        format!(
+            "No workspace root found above {} for {}",
+            manifest_dir.display(),
+            WORKSPACE_PREFIX
         )
+    })
+}
+
+fn normalize(path: &Path) -> PathBuf {
+    let mut result = PathBuf::new();
+
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if !result.pop() {
+                    result.push(component);
+                }
+            }
+            c => result.push(c),
+        }
     }
+
+    result
+}
+
+fn parse_dir(path: &Path) -> Result<proc_macro2::TokenStream, String> {
+    let mut files = vec![];
+    let mut vars: Vec<String> = vec![];
+    let mut migrations: Vec<Migration> = vec![];
+
+    let entries = read_dir(path).map_err(|e| {
+        format!(
+            "Failed to read migrations directory {}: {}",
+            path.display(),
+            e
+        )
+    })?;
+
+    for entry in entries {
+        let entry = entry.map_err(|e| {
+            format!(
+                "Failed to read entry in migrations directory {}: {}",
+                path.display(),
+                e
+            )
+        })?;
+
+        let file = entry.path();
+        let file_str = file
+            .to_str()
+            .ok_or_else(|| format!("Migration path {} is not valid UTF-8", file.display()))?
+            .to_owned();
+
+        let sql = fs::read_to_string(&file)
+            .map_err(|e| format!("Failed to read migration {}: {}", file.display(), e))?;
+
+        for name in template_var_names(&sql) {
+            if !vars.contains(&name) {
+                vars.push(name);
+            }
+        }
+
+        let migration: Migration = entry
+            .try_into()
+            .map_err(|e| format!("Failed to load migration {}: {}", file.display(), e))?;
+
+        migrations.push(migration);
+        files.push(file_str);
+    }
+
+    migrations.sort_by(|a, b| (a.version, &a.name).cmp(&(b.version, &b.name)));
+
+    // Referencing every file through include_bytes! and every variable
+    // through env! makes rustc record them as dependencies, so changing
+    // either triggers a rebuild.
+    Ok(quote! {
+        {
+            #( const _: &[u8] = include_bytes!(#files); )*
+            #( const _: &str = env!(#vars); )*
+
+            pgutils::migrate::Migrator::new(
+                vec![ #(#migrations),* ]
+            )
+        }
+    })
+}
+
+#[test]
+fn resolve_paths() {
+    let root = env::temp_dir().join(format!("pgutils_macros_{}", std::process::id()));
+    let nested = root.join("nested");
+    let manifest_dir = nested.join("crate");
+
+    fs::create_dir_all(&manifest_dir).unwrap();
+    fs::write(root.join("Cargo.toml"), "[workspace]\n").unwrap();
+    fs::write(
+        nested.join("Cargo.toml"),
+        "[workspace]\nmembers = [\"crate\"]\n",
+    )
+    .unwrap();
+    fs::write(manifest_dir.join("Cargo.toml"), "[package]\n").unwrap();
+
+    let resolve = |dir| resolve_dir(&manifest_dir, dir).unwrap();
+
+    assert_eq!(nested.join("migrations"), resolve("$workspace/migrations"));
+    assert_eq!(nested, resolve("$workspace"));
+    assert_eq!(
+        manifest_dir.join("$workspaces/migrations"),
+        resolve("$workspaces/migrations")
+    );
+    assert_eq!(nested.join("migrations"), resolve("../migrations"));
+    assert_eq!(
+        PathBuf::from("/srv/migrations"),
+        resolve("/srv/app/../migrations")
+    );
+
+    fs::remove_dir_all(&root).unwrap();
 }
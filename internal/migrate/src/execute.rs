@@ -0,0 +1,30 @@
+use std::future::Future;
+
+use tokio_postgres::GenericClient;
+
+use crate::MigrationError;
+
+/// The target that migration SQL is executed against. Implemented for
+/// tokio-postgres clients and by `Recorder`.
+pub trait Execute {
+    /// Executes a single statement.
+    fn execute(&mut self, sql: &str) -> impl Future<Output = Result<(), MigrationError>>;
+
+    /// Executes a batch that may contain several statements, as produced by
+    /// `StatementDelimiter::GoBatch`.
+    fn batch_execute(&mut self, sql: &str) -> impl Future<Output = Result<(), MigrationError>>;
+}
+
+pub(crate) struct Conn<'a, C>(pub(crate) &'a C);
+
+impl<C: GenericClient> Execute for Conn<'_, C> {
+    async fn execute(&mut self, sql: &str) -> Result<(), MigrationError> {
+        self.0.execute(sql, &[]).await?;
+        Ok(())
+    }
+
+    async fn batch_execute(&mut self, sql: &str) -> Result<(), MigrationError> {
+        self.0.batch_execute(sql).await?;
+        Ok(())
+    }
+}
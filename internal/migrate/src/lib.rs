@@ -1,3 +1,15 @@
+mod execute;
+#[cfg(feature = "recorder")]
+mod recorder;
+#[cfg(feature = "testing")]
+mod testing;
+
+#[cfg(feature = "recorder")]
+pub use execute::Execute;
+#[cfg(feature = "recorder")]
+pub use recorder::Recorder;
+
+use execute::Conn;
 use fs::DirEntry;
 use lazy_static::lazy_static;
 use proc_macro2::TokenStream;
@@ -5,14 +17,23 @@ use quote::ToTokens;
 use quote::{quote, TokenStreamExt};
 use regex::Regex;
 use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
 use std::convert::TryFrom;
+use std::fmt;
 use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use thiserror::Error;
-use tokio_postgres::{Client, Row};
+use tokio::sync::mpsc::Sender;
+use tokio_postgres::{error::SqlState, Client, GenericClient, Row, Transaction};
 
 lazy_static! {
     static ref FILENAME_REGEX: Regex =
         Regex::new(r"^(?P<version>[0-9]+)_(?P<name>[a-z_]+)\.sql$").unwrap();
+    static ref NAME_REGEX: Regex = Regex::new(r"^[a-z_]+$").unwrap();
+    static ref TABLE_NAME_REGEX: Regex =
+        Regex::new(r"^([A-Za-z_][A-Za-z0-9_]*\.)?[A-Za-z_][A-Za-z0-9_]*$").unwrap();
 }
 
 #[derive(Error, Debug)]
@@ -23,6 +44,44 @@ pub enum MigrationError {
     #[error("Checksum of already applied migration does not match")]
     ChecksumError,
 
+    #[error("Migration name {0:?} is invalid")]
+    NameError(String),
+
+    #[error("Migrations table name {0:?} is invalid")]
+    TableNameError(String),
+
+    #[error("Migration version {0} is defined more than once")]
+    DuplicateVersion(i64),
+
+    #[error("Migration version {missing} is missing from the sequence")]
+    VersionGap { missing: i64 },
+
+    #[error("Migration {0} is older than the latest applied migration")]
+    OutOfOrderMigration(i64),
+
+    #[error("Migration {0} cannot run in a single transaction with the others")]
+    NotTransactional(i64),
+
+    #[error("Migrations are locked by another process")]
+    Locked,
+
+    #[error("The migrations lock was taken over by another process")]
+    LockLost,
+
+    /// A statement of a migration failed. `statement_index` counts from 1.
+    #[error("Migration {version}, statement {statement_index} failed: {source}")]
+    ApplyError {
+        version: i64,
+        statement_index: usize,
+        source: tokio_postgres::Error,
+    },
+
+    #[error("Environment variable {0} is not defined")]
+    UndefinedVariable(String),
+
+    #[error("Unknown checksum algorithm: {0}")]
+    UnknownChecksumAlgo(String),
+
     #[error(transparent)]
     PostgresError(#[from] tokio_postgres::Error),
 
@@ -33,11 +92,110 @@ pub enum MigrationError {
     IOError(#[from] std::io::Error),
 }
 
+/// Algorithm used to compute the checksum of a migration.
+///
+/// The algorithm is stored next to the checksum of every applied migration, so
+/// rows written with one algorithm keep validating after switching to another.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ChecksumAlgo {
+    /// Sha256 over the raw bytes of the migration.
+    #[default]
+    Sha256,
+
+    /// Sha256 over the migration with trailing whitespace stripped from every
+    /// line and trailing blank lines removed.
+    Sha256Normalized,
+
+    /// Sha256 over the sorted, trimmed statements of the migration, so that
+    /// reordering statements does not change the checksum. Only use this if
+    /// the statements of a migration are independent of each other, as the
+    /// order in which they are applied is no longer verified.
+    Sha256Unordered,
+}
+
+impl ChecksumAlgo {
+    pub fn checksum(&self, sql: &str) -> String {
+        match self {
+            ChecksumAlgo::Sha256 => format!("{:x}", Sha256::digest(sql.as_bytes())),
+            ChecksumAlgo::Sha256Normalized => {
+                let normalized = sql.lines().map(str::trim_end).collect::<Vec<_>>();
+                let normalized = normalized.join("\n");
+                let normalized = normalized.trim_end_matches('\n');
+
+                format!("{:x}", Sha256::digest(normalized.as_bytes()))
+            }
+            ChecksumAlgo::Sha256Unordered => {
+                let mut statements = split_statements(sql);
+                statements.sort_unstable();
+
+                format!("{:x}", Sha256::digest(statements.join(";\n").as_bytes()))
+            }
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ChecksumAlgo::Sha256 => "sha256",
+            ChecksumAlgo::Sha256Normalized => "sha256-normalized",
+            ChecksumAlgo::Sha256Unordered => "sha256-unordered",
+        }
+    }
+}
+
+impl fmt::Display for ChecksumAlgo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for ChecksumAlgo {
+    type Err = MigrationError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sha256" => Ok(ChecksumAlgo::Sha256),
+            "sha256-normalized" => Ok(ChecksumAlgo::Sha256Normalized),
+            "sha256-unordered" => Ok(ChecksumAlgo::Sha256Unordered),
+            _ => Err(MigrationError::UnknownChecksumAlgo(s.to_owned())),
+        }
+    }
+}
+
+/// What `migrate` does when an applied migration no longer matches its
+/// checksum.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MismatchPolicy {
+    /// Fail with `MigrationError::ChecksumError`.
+    #[default]
+    Error,
+
+    /// Emit a `tracing` warning, if the `tracing` feature is enabled, and
+    /// continue.
+    Warn,
+
+    /// Continue silently.
+    Ignore,
+}
+
+/// How a migration is split into the statements executed one by one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum StatementDelimiter {
+    /// Statements end with `;`, outside of quotes and comments.
+    #[default]
+    Semicolon,
+
+    /// Batches are separated by lines containing only `GO`, as in SQL Server
+    /// scripts. Each batch may contain several statements.
+    GoBatch,
+}
+
 #[derive(Debug)]
 pub struct Migration {
     pub checksum: String,
+    pub labels: Vec<String>,
     pub name: String,
     pub sql: String,
+    pub transactional: bool,
     pub version: i64,
 }
 
@@ -64,107 +222,1107 @@ impl TryFrom<DirEntry> for Migration {
             .ok_or(MigrationError::FilenameError)?
             .parse()?;
 
-        let sql = fs::read_to_string(&entry.path())?;
-        let checksum = format!("{:x}", Sha256::digest(sql.as_bytes()));
+        let sql = fs::read_to_string(entry.path())?;
+        let sql = substitute_vars(&sql, |name| std::env::var(name).ok())?;
+
+        Ok(Self::new(version, name, sql))
+    }
+}
+
+impl Migration {
+    /// Creates a migration from its SQL source. Line endings are normalized to
+    /// `\n`, so the checksum does not depend on how the file was checked out.
+    ///
+    /// Options are read from `-- pgutils: ...` comments among the leading
+    /// comment lines:
+    ///
+    /// - `labels=destructive,slow` attaches labels, which are stored when the
+    ///   migration is applied.
+    /// - `no-transaction` applies the statements outside of a transaction, as
+    ///   required by e.g. `CREATE INDEX CONCURRENTLY`. Completed statements are
+    ///   checkpointed, so a failed migration resumes at the failed statement
+    ///   when run again. If the migration was edited in the meantime, it
+    ///   starts over from its first statement instead. A statement
+    ///   interrupted after it succeeded but before its checkpoint was stored
+    ///   also runs again, so statements should be idempotent, e.g. by using
+    ///   `IF NOT EXISTS`.
+    pub fn new(version: i64, name: String, sql: String) -> Self {
+        let sql = sql.replace("\r\n", "\n");
+        let checksum = ChecksumAlgo::Sha256.checksum(&sql);
+
+        let labels = directives(&sql)
+            .filter_map(|d| d.strip_prefix("labels="))
+            .flat_map(|labels| labels.split(','))
+            .map(str::trim)
+            .filter(|label| !label.is_empty())
+            .map(String::from)
+            .collect();
 
-        Ok(Self {
+        let transactional = !directives(&sql).any(|d| d == "no-transaction");
+
+        Self {
             checksum,
+            labels,
             name,
             sql,
+            transactional,
             version,
+        }
+    }
+
+    /// Returns the label used for the migration in messages, in the form of
+    /// its filename without extension, e.g. `0007_add_users`.
+    pub fn display_id(&self) -> String {
+        format!("{:04}_{}", self.version, self.name)
+    }
+
+    /// Computes the checksum of this migration using the given algorithm.
+    pub fn checksum_with(&self, algo: ChecksumAlgo) -> String {
+        match algo {
+            ChecksumAlgo::Sha256 => self.checksum.clone(),
+            _ => algo.checksum(&self.sql),
+        }
+    }
+
+    /// Splits the migration into its individual, trimmed statements.
+    ///
+    /// Semicolons inside quoted strings, quoted identifiers, dollar-quoted
+    /// bodies and comments do not end a statement.
+    pub fn statements(&self) -> Vec<&str> {
+        split_statements(&self.sql)
+    }
+
+    /// Splits the migration into the trimmed pieces executed separately when
+    /// using the given delimiter.
+    pub fn statements_with(&self, delimiter: StatementDelimiter) -> Vec<&str> {
+        match delimiter {
+            StatementDelimiter::Semicolon => self.statements(),
+            StatementDelimiter::GoBatch => split_go_batches(&self.sql),
+        }
+    }
+}
+
+/// Returns the names of the `${NAME}` placeholders in `sql`, in order of first
+/// use. `embed!` uses this to make the compiler track the variables.
+pub fn template_var_names(sql: &str) -> Vec<String> {
+    let mut names: Vec<String> = vec![];
+
+    let _ = substitute_vars(sql, |name| {
+        if !names.iter().any(|n| n == name) {
+            names.push(name.to_owned());
+        }
+
+        Some(String::new())
+    });
+
+    names
+}
+
+/// Replaces every `${NAME}` in `sql` with the value returned by `lookup`,
+/// failing if it returns `None`. `$${` stands for a literal `${`, other uses of
+/// `$` are left alone.
+fn substitute_vars(
+    sql: &str,
+    mut lookup: impl FnMut(&str) -> Option<String>,
+) -> Result<String, MigrationError> {
+    let mut result = String::with_capacity(sql.len());
+    let mut rest = sql;
+
+    while let Some(start) = rest.find('$') {
+        result.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        if let Some(escaped) = rest.strip_prefix("$${") {
+            result.push_str("${");
+            rest = escaped;
+            continue;
+        }
+
+        let name = rest
+            .strip_prefix("${")
+            .and_then(|r| r.split_once('}'))
+            .map(|(name, _)| name)
+            .filter(|name| is_var_name(name));
+
+        match name {
+            Some(name) => {
+                let value =
+                    lookup(name).ok_or_else(|| MigrationError::UndefinedVariable(name.into()))?;
+
+                result.push_str(&value);
+                rest = &rest[name.len() + 3..];
+            }
+            None => {
+                result.push('$');
+                rest = &rest[1..];
+            }
+        }
+    }
+
+    result.push_str(rest);
+    Ok(result)
+}
+
+fn is_var_name(name: &str) -> bool {
+    let mut chars = name.chars();
+
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Yields the text following `pgutils:` in the leading comment lines.
+fn directives(sql: &str) -> impl Iterator<Item = &str> {
+    sql.lines()
+        .map(str::trim)
+        .take_while(|line| line.is_empty() || line.starts_with("--"))
+        .filter_map(|line| {
+            line.trim_start_matches('-')
+                .trim_start()
+                .strip_prefix("pgutils:")
+                .map(str::trim)
         })
+}
+
+fn split_go_batches(sql: &str) -> Vec<&str> {
+    let mut batches = vec![];
+    let mut start = 0;
+    let mut offset = 0;
+
+    for line in sql.split_inclusive('\n') {
+        if line.trim().eq_ignore_ascii_case("GO") {
+            batches.push(&sql[start..offset]);
+            start = offset + line.len();
+        }
+
+        offset += line.len();
+    }
+
+    batches.push(&sql[start..]);
+
+    batches
+        .into_iter()
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
+fn split_statements(sql: &str) -> Vec<&str> {
+    let bytes = sql.as_bytes();
+    let mut statements = vec![];
+    let mut start = 0;
+    let mut i = 0;
+
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\'' | b'"' => {
+                let quote = bytes[i];
+                i += 1;
+
+                while i < bytes.len() && bytes[i] != quote {
+                    i += 1;
+                }
+            }
+            b'-' if bytes.get(i + 1) == Some(&b'-') => {
+                while i < bytes.len() && bytes[i] != b'\n' {
+                    i += 1;
+                }
+            }
+            b'/' if bytes.get(i + 1) == Some(&b'*') => {
+                i += 2;
+
+                while i < bytes.len() && !(bytes[i] == b'*' && bytes.get(i + 1) == Some(&b'/')) {
+                    i += 1;
+                }
+
+                i += 1;
+            }
+            b'$' => {
+                if let Some(len) = dollar_tag_len(&bytes[i..]) {
+                    let tag = &sql[i..i + len];
+
+                    i = match sql[i + len..].find(tag) {
+                        Some(end) => i + len + end + len - 1,
+                        None => bytes.len(),
+                    };
+                }
+            }
+            b';' => {
+                statements.push(&sql[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+
+        i += 1;
     }
+
+    statements.push(&sql[start.min(sql.len())..]);
+
+    statements
+        .into_iter()
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
+/// Returns the length of the dollar-quote tag (e.g. `$$` or `$body$`) at the
+/// start of `bytes`, if there is one.
+fn dollar_tag_len(bytes: &[u8]) -> Option<usize> {
+    for (i, b) in bytes.iter().enumerate().skip(1) {
+        match b {
+            b'$' => return Some(i + 1),
+            b'_' => {}
+            b if b.is_ascii_alphabetic() => {}
+            b if b.is_ascii_digit() && i > 1 => {}
+            _ => return None,
+        }
+    }
+
+    None
 }
 
 impl ToTokens for Migration {
     fn to_tokens(&self, tokens: &mut TokenStream) {
         let Migration {
             checksum,
+            labels,
             name,
             sql,
+            transactional,
             version,
         } = &self;
 
         let ts = quote! {
             pgutils::migrate::Migration {
                 checksum: String::from(#checksum),
+                labels: vec![#(String::from(#labels)),*],
                 name: String::from(#name),
                 sql: String::from(#sql),
+                transactional: #transactional,
                 version: #version,
             }
         };
 
-        tokens.append_all(ts.into_iter());
+        tokens.append_all(ts);
     }
 }
 
 struct AppliedMigration {
     checksum: String,
+    checksum_algo: ChecksumAlgo,
+    created_at: SystemTime,
+    name: String,
     version: i64,
 }
 
+/// Where `migrate` applies migrations: each in its own transaction, or all in
+/// one with `Migrator::single_transaction`.
+enum Target<'a, 'b> {
+    PerMigration(&'a mut Client),
+    Single(&'a Transaction<'b>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MigrationState {
+    /// Applied and unchanged since.
+    Applied { at: SystemTime },
+
+    /// Not applied yet.
+    Pending,
+
+    /// Applied, but the checksum no longer matches the source.
+    Drifted,
+
+    /// Applied, but there is no longer a migration with this version.
+    Orphaned,
+}
+
+#[derive(Debug, Clone)]
+pub struct MigrationStatus {
+    pub version: i64,
+    pub name: String,
+    pub state: MigrationState,
+}
+
+/// Progress of `Migrator::migrate_with_events`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MigrationEvent {
+    Started { version: i64 },
+    Applied { version: i64, ms: u64 },
+    Failed { version: i64, error: String },
+}
+
 pub struct Migrator {
     pub migrations: Vec<Migration>,
+    table_name: String,
+    checksum_algo: ChecksumAlgo,
+    allow_out_of_order: bool,
+    reject_version_gaps: bool,
+    delimiter: StatementDelimiter,
+    mismatch_policy: MismatchPolicy,
+    on_progress: Option<Box<dyn Fn(usize, usize) + Send + Sync>>,
+    analyze_after: Vec<String>,
+    single_transaction: bool,
+    lock_stale_after: Option<Duration>,
+    statement_timeout: Option<Duration>,
 }
 
 impl Migrator {
     pub fn new(migrations: Vec<Migration>) -> Self {
-        Migrator { migrations }
+        Migrator {
+            migrations,
+            table_name: String::from("migrations"),
+            checksum_algo: ChecksumAlgo::default(),
+            allow_out_of_order: false,
+            reject_version_gaps: false,
+            delimiter: StatementDelimiter::default(),
+            mismatch_policy: MismatchPolicy::default(),
+            on_progress: None,
+            analyze_after: vec![],
+            single_transaction: false,
+            lock_stale_after: None,
+            statement_timeout: None,
+        }
+    }
+
+    /// Allows applying pending migrations with a version lower than the latest
+    /// applied one, as happens after merging branches. By default such
+    /// migrations are rejected with `MigrationError::OutOfOrderMigration`.
+    pub fn allow_out_of_order(mut self, allow: bool) -> Self {
+        self.allow_out_of_order = allow;
+        self
+    }
+
+    /// Requires versions to form a contiguous sequence starting at the lowest
+    /// one. This is off by default, as it does not suit timestamp versions.
+    pub fn reject_version_gaps(mut self, reject: bool) -> Self {
+        self.reject_version_gaps = reject;
+        self
+    }
+
+    /// Sets the table used to track applied migrations, `migrations` by
+    /// default. The name may be qualified with a schema (`schema.table`) and
+    /// is quoted, so reserved words can be used.
+    pub fn table_name(mut self, name: &str) -> Self {
+        self.table_name = name.to_owned();
+        self
+    }
+
+    /// Sets how checksum mismatches of applied migrations are handled. They
+    /// are fatal by default.
+    pub fn on_checksum_mismatch(mut self, policy: MismatchPolicy) -> Self {
+        self.mismatch_policy = policy;
+        self
+    }
+
+    /// Registers a callback invoked before each migration is applied with the
+    /// number of migrations applied so far and the total number pending.
+    pub fn on_progress(mut self, f: impl Fn(usize, usize) + Send + Sync + 'static) -> Self {
+        self.on_progress = Some(Box::new(f));
+        self
+    }
+
+    /// Runs `ANALYZE` on the given tables after `migrate` applied at least one
+    /// migration, so query plans reflect backfilled data right away. It only
+    /// runs when all pending migrations applied successfully, after their
+    /// transactions committed, including with `single_transaction`. Names
+    /// follow the same rules as `Migrator::table_name`.
+    pub fn analyze_after(mut self, tables: Vec<String>) -> Self {
+        self.analyze_after = tables;
+        self
+    }
+
+    /// Applies all pending migrations of a `migrate` run in one transaction, so
+    /// a failing migration also rolls back the ones applied before it. This
+    /// cannot be combined with `no-transaction` migrations, which make
+    /// `validate` fail with `MigrationError::NotTransactional`.
+    pub fn single_transaction(mut self) -> Self {
+        self.single_transaction = true;
+        self
+    }
+
+    /// Makes `migrate` hold a lock stored in the `{table}_lock` table, failing
+    /// with `MigrationError::Locked` if another process holds it. Unlike
+    /// advisory locks, this works behind poolers in transaction mode, such as
+    /// PgBouncer. A lock older than `stale_after` is considered abandoned,
+    /// e.g. by a crashed process, and taken over. The lock is refreshed before
+    /// each migration, so `stale_after` must be longer than the slowest single
+    /// migration; a process whose lock was taken over fails with
+    /// `MigrationError::LockLost` before its next migration.
+    pub fn table_lock(mut self, stale_after: Duration) -> Self {
+        self.lock_stale_after = Some(stale_after);
+        self
+    }
+
+    /// Limits how long each statement of a migration may run, so e.g. a stuck
+    /// table rewrite fails instead of blocking the deployment. It is set with
+    /// `SET LOCAL` in the migration's transaction, which for `migrate_in_tx`
+    /// is the caller's. `no-transaction` migrations set it for the session
+    /// and restore the previous value afterwards.
+    pub fn statement_timeout(mut self, timeout: Duration) -> Self {
+        self.statement_timeout = Some(timeout);
+        self
+    }
+
+    /// Sets how migrations are split into statements. Defaults to
+    /// `StatementDelimiter::Semicolon`.
+    pub fn with_delimiter(mut self, delimiter: StatementDelimiter) -> Self {
+        self.delimiter = delimiter;
+        self
     }
 
+    /// Creates a migrator from `(version, name, sql)` tuples instead of files.
+    /// Names follow the same rules as migration filenames.
+    pub fn from_sources(sources: Vec<(i64, String, String)>) -> Result<Self, MigrationError> {
+        let mut migrations = vec![];
+
+        for (version, name, sql) in sources {
+            if !NAME_REGEX.is_match(&name) {
+                return Err(MigrationError::NameError(name));
+            }
+
+            migrations.push(Migration::new(version, name, sql));
+        }
+
+        Self::from_unsorted(migrations)
+    }
+
+    /// Loads migrations from a directory at runtime, following the same rules
+    /// as `embed!` does at compile time, including the substitution of
+    /// `${NAME}` placeholders, here with the environment at runtime.
+    pub fn from_dir(path: &Path) -> Result<Self, MigrationError> {
+        let mut migrations = vec![];
+
+        for entry in fs::read_dir(path)? {
+            migrations.push(Migration::try_from(entry?)?);
+        }
+
+        Self::from_unsorted(migrations)
+    }
+
+    fn from_unsorted(mut migrations: Vec<Migration>) -> Result<Self, MigrationError> {
+        migrations.sort_by(|a, b| (a.version, &a.name).cmp(&(b.version, &b.name)));
+
+        if let Some(w) = migrations.windows(2).find(|w| w[0].version == w[1].version) {
+            return Err(MigrationError::DuplicateVersion(w[0].version));
+        }
+
+        Ok(Self::new(migrations))
+    }
+
+    /// Sets the algorithm used to checksum newly applied migrations. Already
+    /// applied migrations are always verified with the algorithm they were
+    /// recorded with.
+    pub fn checksum_algo(mut self, algo: ChecksumAlgo) -> Self {
+        self.checksum_algo = algo;
+        self
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
     pub async fn migrate(&self, db: &mut Client) -> Result<(), MigrationError> {
+        self.migrate_with(db, None).await
+    }
+
+    /// Like `migrate`, but sends an event to `events` before and after each
+    /// migration, e.g. to show live progress. Events are dropped if the
+    /// receiver is gone, migrating continues regardless.
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    pub async fn migrate_with_events(
+        &self,
+        db: &mut Client,
+        events: Sender<MigrationEvent>,
+    ) -> Result<(), MigrationError> {
+        self.migrate_with(db, Some(&events)).await
+    }
+
+    async fn migrate_with(
+        &self,
+        db: &mut Client,
+        events: Option<&Sender<MigrationEvent>>,
+    ) -> Result<(), MigrationError> {
+        let holder = match self.lock_stale_after {
+            Some(stale_after) => Some(self.acquire_lock(db, stale_after).await?),
+            None => None,
+        };
+
+        let result = self.migrate_pending(db, events, holder.as_deref()).await;
+
+        let released = match &holder {
+            Some(holder) => self.release_lock(db, holder).await,
+            None => Ok(()),
+        };
+
+        result.and(released)
+    }
+
+    async fn migrate_pending(
+        &self,
+        db: &mut Client,
+        events: Option<&Sender<MigrationEvent>>,
+        holder: Option<&str>,
+    ) -> Result<(), MigrationError> {
+        let applied = if self.single_transaction {
+            let tx = db.transaction().await?;
+            let applied = self
+                .apply_pending(Target::Single(&tx), events, holder)
+                .await?;
+            tx.commit().await?;
+            applied
+        } else {
+            self.apply_pending(Target::PerMigration(&mut *db), events, holder)
+                .await?
+        };
+
+        if applied > 0 {
+            for table in &self.analyze_after {
+                db.batch_execute(&format!("ANALYZE {}", quote_table_name(table)?))
+                    .await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Applies the pending migrations to `target`, returning how many there
+    /// were.
+    async fn apply_pending(
+        &self,
+        mut target: Target<'_, '_>,
+        events: Option<&Sender<MigrationEvent>>,
+        holder: Option<&str>,
+    ) -> Result<usize, MigrationError> {
+        let pending = match &target {
+            Target::PerMigration(db) => self.pending_migrations(&**db).await?,
+            Target::Single(tx) => self.pending_migrations(*tx).await?,
+        };
+
+        let send = |event| async move {
+            if let Some(events) = events {
+                let _ = events.send(event).await;
+            }
+        };
+
+        for (i, migration) in pending.iter().enumerate() {
+            let version = migration.version;
+
+            if let Some(holder) = holder {
+                match &target {
+                    Target::PerMigration(db) => self.refresh_lock(&**db, holder).await?,
+                    Target::Single(tx) => self.refresh_lock(*tx, holder).await?,
+                }
+            }
+
+            self.report_progress(i, pending.len());
+            send(MigrationEvent::Started { version }).await;
+
+            let start = Instant::now();
+
+            let result = match &mut target {
+                Target::PerMigration(db) => self.apply_migration(db, migration).await,
+                Target::Single(tx) => self.run_migration(*tx, migration).await,
+            };
+
+            match result {
+                Ok(()) => {
+                    let ms = start.elapsed().as_millis() as u64;
+
+                    #[cfg(feature = "tracing")]
+                    tracing::debug!(version, name = %migration.name, ms, "migration applied");
+
+                    send(MigrationEvent::Applied { version, ms }).await;
+                }
+                Err(e) => {
+                    let error = e.to_string();
+                    send(MigrationEvent::Failed { version, error }).await;
+                    return Err(e);
+                }
+            }
+        }
+
+        Ok(pending.len())
+    }
+
+    /// Applies all pending migrations within a transaction owned by the caller,
+    /// without committing it.
+    ///
+    /// This makes the whole run all-or-nothing: rolling back the transaction
+    /// discards every migration applied by this call. In exchange, migrations
+    /// must not contain statements that cannot run inside a transaction block,
+    /// such as `CREATE INDEX CONCURRENTLY`. Pending `no-transaction` migrations
+    /// make it fail with `MigrationError::NotTransactional` before anything is
+    /// applied.
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    pub async fn migrate_in_tx(&self, tx: &Transaction<'_>) -> Result<(), MigrationError> {
+        let pending = self.pending_migrations(tx).await?;
+
+        if let Some(m) = pending.iter().find(|m| !m.transactional) {
+            return Err(MigrationError::NotTransactional(m.version));
+        }
+
+        for (i, migration) in pending.iter().enumerate() {
+            self.report_progress(i, pending.len());
+            self.run_migration(tx, migration).await?;
+
+            #[cfg(feature = "tracing")]
+            tracing::debug!(
+                version = migration.version,
+                name = %migration.name,
+                "migration applied"
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Returns the SQL of all pending migrations in the order `migrate` would
+    /// apply them, each preceded by a `-- migration 0007_add_users` comment,
+    /// e.g. for review before applying to production. Nothing is executed,
+    /// not even creating the migrations table.
+    pub async fn plan_sql(&self, db: &Client) -> Result<String, MigrationError> {
+        self.validate()?;
+
+        let current = self.read_applied_migrations(db).await?;
+
+        Ok(render_plan(&self.select_pending(&current)?))
+    }
+
+    /// Cheaply checks whether every migration has been applied, by comparing
+    /// the applied versions against the known ones. Applied migrations that
+    /// are no longer known also make it return `false`. Checksums are not
+    /// verified.
+    ///
+    /// The result may already be outdated when it is returned if another
+    /// process migrates concurrently, so a `false` should still be followed
+    /// by `migrate`, which handles that case.
+    pub async fn is_up_to_date(&self, db: &Client) -> Result<bool, MigrationError> {
+        let sql = format!(
+            "SELECT count(*) FILTER (WHERE version = ANY($1)), count(*) FROM {}",
+            self.table()?
+        );
+        let known = self
+            .migrations
+            .iter()
+            .map(|m| m.version)
+            .collect::<Vec<_>>();
+
+        let row = match db.query_one(&sql, &[&known]).await {
+            Ok(row) => row,
+            Err(e) if e.code() == Some(&SqlState::UNDEFINED_TABLE) => {
+                return Ok(self.migrations.is_empty());
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let applied: i64 = row.try_get(0)?;
+        let count: i64 = row.try_get(1)?;
+
+        Ok(applied == known.len() as i64 && count == applied)
+    }
+
+    /// Checks the migrations for problems that can be detected without a
+    /// database. Called by `migrate` before applying anything.
+    pub fn validate(&self) -> Result<(), MigrationError> {
+        self.table()?;
+
+        for table in &self.analyze_after {
+            quote_table_name(table)?;
+        }
+
+        let mut by_version = HashMap::with_capacity(self.migrations.len());
+
+        for migration in &self.migrations {
+            if by_version.insert(migration.version, migration).is_some() {
+                return Err(MigrationError::DuplicateVersion(migration.version));
+            }
+        }
+
+        if self.single_transaction {
+            if let Some(m) = self.migrations.iter().find(|m| !m.transactional) {
+                return Err(MigrationError::NotTransactional(m.version));
+            }
+        }
+
+        if self.reject_version_gaps {
+            for w in self.migrations.windows(2) {
+                if w[1].version > w[0].version + 1 {
+                    return Err(MigrationError::VersionGap {
+                        missing: w[0].version + 1,
+                    });
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Marks all migrations up to and including `up_to` as applied without
+    /// running their SQL. This is meant for adopting an existing database whose
+    /// schema was created outside of the migration system.
+    pub async fn baseline(&self, db: &mut Client, up_to: i64) -> Result<(), MigrationError> {
         self.ensure_table(db).await?;
 
         let current = self.get_applied_migrations(db).await?;
+        let applied = Self::by_version(&current);
+        let tx = db.transaction().await?;
+
+        for migration in self.migrations.iter().filter(|m| m.version <= up_to) {
+            if !applied.contains_key(&migration.version) {
+                self.record_migration(&tx, migration).await?;
+            }
+        }
+
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    /// Reports the state of every known migration, including applied
+    /// migrations that no longer exist, ordered by version. This only reads
+    /// the migrations table; if it does not exist, all migrations are pending.
+    pub async fn status(&self, db: &Client) -> Result<Vec<MigrationStatus>, MigrationError> {
+        let current = self.read_applied_migrations(db).await?;
+        let applied = Self::by_version(&current);
+        let mut result = vec![];
 
         for migration in &self.migrations {
-            match current.iter().find(|a| a.version == migration.version) {
-                None => self.apply_migration(db, migration).await?,
-                Some(a) => {
-                    if a.checksum != migration.checksum {
-                        return Err(MigrationError::ChecksumError);
+            let state = match applied.get(&migration.version) {
+                None => MigrationState::Pending,
+                Some(a) if a.checksum != migration.checksum_with(a.checksum_algo) => {
+                    MigrationState::Drifted
+                }
+                Some(a) => MigrationState::Applied { at: a.created_at },
+            };
+
+            result.push(MigrationStatus {
+                version: migration.version,
+                name: migration.name.clone(),
+                state,
+            });
+        }
+
+        let known = self
+            .migrations
+            .iter()
+            .map(|m| m.version)
+            .collect::<HashSet<_>>();
+
+        for applied in current {
+            if !known.contains(&applied.version) {
+                result.push(MigrationStatus {
+                    version: applied.version,
+                    name: applied.name,
+                    state: MigrationState::Orphaned,
+                });
+            }
+        }
+
+        result.sort_by_key(|s| s.version);
+
+        Ok(result)
+    }
+
+    /// Rewrites the stored checksum of every applied migration that no longer
+    /// matches its source, without running any migration SQL. Returns the
+    /// versions that were repaired.
+    pub async fn repair(&self, db: &mut Client) -> Result<Vec<i64>, MigrationError> {
+        self.ensure_table(db).await?;
+
+        let current = self.get_applied_migrations(db).await?;
+        let applied = Self::by_version(&current);
+        let tx = db.transaction().await?;
+        let mut repaired = vec![];
+
+        for migration in &self.migrations {
+            let applied = match applied.get(&migration.version) {
+                Some(a) => a,
+                None => continue,
+            };
+
+            if applied.checksum == migration.checksum_with(applied.checksum_algo) {
+                continue;
+            }
+
+            tx.execute(
+                &format!(
+                    r#"
+                        UPDATE {}
+                        SET checksum = $2, checksum_algo = $3
+                        WHERE version = $1
+                    "#,
+                    self.table()?
+                ),
+                &[
+                    &migration.version,
+                    &migration.checksum_with(self.checksum_algo),
+                    &self.checksum_algo.as_str(),
+                ],
+            )
+            .await?;
+
+            repaired.push(migration.version);
+        }
+
+        tx.commit().await?;
+
+        Ok(repaired)
+    }
+
+    async fn pending_migrations<C: GenericClient>(
+        &self,
+        db: &C,
+    ) -> Result<Vec<&Migration>, MigrationError> {
+        self.validate()?;
+        self.ensure_table(db).await?;
+
+        let current = self.get_applied_migrations(db).await?;
+
+        self.select_pending(&current)
+    }
+
+    /// Compares the known migrations against the applied ones, returning the
+    /// migrations still to apply in order.
+    fn select_pending(
+        &self,
+        current: &[AppliedMigration],
+    ) -> Result<Vec<&Migration>, MigrationError> {
+        let applied = Self::by_version(current);
+        let max_applied = current.iter().map(|a| a.version).max();
+        let mut pending = vec![];
+
+        for migration in &self.migrations {
+            match applied.get(&migration.version) {
+                None => {
+                    if !self.allow_out_of_order
+                        && max_applied.is_some_and(|max| migration.version < max)
+                    {
+                        return Err(MigrationError::OutOfOrderMigration(migration.version));
+                    }
+
+                    pending.push(migration);
+                }
+                Some(a) if a.checksum != migration.checksum_with(a.checksum_algo) => {
+                    match self.mismatch_policy {
+                        MismatchPolicy::Error => return Err(MigrationError::ChecksumError),
+                        #[cfg(feature = "tracing")]
+                        MismatchPolicy::Warn => tracing::warn!(
+                            version = migration.version,
+                            name = %migration.name,
+                            "checksum of applied migration does not match"
+                        ),
+                        _ => {}
                     }
                 }
+                #[cfg(feature = "tracing")]
+                Some(_) => tracing::debug!(
+                    version = migration.version,
+                    name = %migration.name,
+                    "migration already applied, checksum ok"
+                ),
+                #[cfg(not(feature = "tracing"))]
+                Some(_) => {}
             };
         }
 
-        Ok(())
+        Ok(pending)
     }
 
-    async fn ensure_table(&self, db: &Client) -> Result<u64, tokio_postgres::Error> {
-        db.execute(
+    fn report_progress(&self, applied: usize, total: usize) {
+        if let Some(f) = &self.on_progress {
+            f(applied, total);
+        }
+    }
+
+    fn by_version(current: &[AppliedMigration]) -> HashMap<i64, &AppliedMigration> {
+        current.iter().map(|a| (a.version, a)).collect()
+    }
+
+    /// Returns the validated and quoted name of the migrations table.
+    fn table(&self) -> Result<String, MigrationError> {
+        self.quoted_table("")
+    }
+
+    /// Returns the quoted name of the table storing the progress of migrations
+    /// applied outside of a transaction.
+    fn checkpoint_table(&self) -> Result<String, MigrationError> {
+        self.quoted_table("_checkpoints")
+    }
+
+    /// Returns the quoted name of the table holding the lock of `table_lock`.
+    fn lock_table(&self) -> Result<String, MigrationError> {
+        self.quoted_table("_lock")
+    }
+
+    fn quoted_table(&self, suffix: &str) -> Result<String, MigrationError> {
+        quote_table_name(&self.table_name)?;
+        quote_table_name(&format!("{}{}", self.table_name, suffix))
+    }
+
+    async fn ensure_table<C: GenericClient>(&self, db: &C) -> Result<(), MigrationError> {
+        db.batch_execute(&format!(
             r#"
-                CREATE TABLE IF NOT EXISTS migrations (
+                CREATE TABLE IF NOT EXISTS {table} (
                     version     BIGINT PRIMARY KEY,
                     name        TEXT NOT NULL,
                     checksum    VARCHAR(64),
                     created_at  TIMESTAMPTZ NOT NULL DEFAULT current_timestamp
+                );
+
+                ALTER TABLE {table}
+                ADD COLUMN IF NOT EXISTS checksum_algo TEXT NOT NULL DEFAULT 'sha256';
+
+                ALTER TABLE {table}
+                ADD COLUMN IF NOT EXISTS labels TEXT[] NOT NULL DEFAULT '{{}}';
+            "#,
+            table = self.table()?
+        ))
+        .await?;
+
+        Ok(())
+    }
+
+    /// Takes the lock of `table_lock`, returning the token identifying this
+    /// process as its holder.
+    async fn acquire_lock(
+        &self,
+        db: &Client,
+        stale_after: Duration,
+    ) -> Result<String, MigrationError> {
+        let table = self.lock_table()?;
+
+        db.batch_execute(&format!(
+            r#"
+                CREATE TABLE IF NOT EXISTS {} (
+                    id          BOOLEAN PRIMARY KEY DEFAULT true CHECK (id),
+                    holder      TEXT NOT NULL,
+                    locked_at   TIMESTAMPTZ NOT NULL DEFAULT current_timestamp
                 )
             "#,
-            &[],
+            table
+        ))
+        .await?;
+
+        db.execute(
+            &format!(
+                "DELETE FROM {} WHERE locked_at < current_timestamp - make_interval(secs => $1)",
+                table
+            ),
+            &[&stale_after.as_secs_f64()],
+        )
+        .await?;
+
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        let holder = format!("{}-{}", std::process::id(), nanos);
+
+        let inserted = db
+            .execute(
+                &format!(
+                    "INSERT INTO {} (holder) VALUES ($1) ON CONFLICT DO NOTHING",
+                    table
+                ),
+                &[&holder],
+            )
+            .await?;
+
+        match inserted {
+            0 => Err(MigrationError::Locked),
+            _ => Ok(holder),
+        }
+    }
+
+    /// Marks the lock of `table_lock` as still in use by `holder`.
+    async fn refresh_lock<C: GenericClient>(
+        &self,
+        db: &C,
+        holder: &str,
+    ) -> Result<(), MigrationError> {
+        // clock_timestamp, as current_timestamp is fixed for a transaction.
+        let updated = db
+            .execute(
+                &format!(
+                    "UPDATE {} SET locked_at = clock_timestamp() WHERE holder = $1",
+                    self.lock_table()?
+                ),
+                &[&holder],
+            )
+            .await?;
+
+        match updated {
+            0 => Err(MigrationError::LockLost),
+            _ => Ok(()),
+        }
+    }
+
+    async fn release_lock(&self, db: &Client, holder: &str) -> Result<(), MigrationError> {
+        db.execute(
+            &format!("DELETE FROM {} WHERE holder = $1", self.lock_table()?),
+            &[&holder],
         )
-        .await
+        .await?;
+
+        Ok(())
+    }
+
+    async fn get_applied_migrations<C: GenericClient>(
+        &self,
+        db: &C,
+    ) -> Result<Vec<AppliedMigration>, MigrationError> {
+        self.select_applied_migrations(db, "checksum_algo").await
     }
 
-    async fn get_applied_migrations(
+    /// Like `get_applied_migrations`, but without relying on `ensure_table`:
+    /// a missing table means nothing was applied, and a table created before
+    /// the `checksum_algo` column was added only holds Sha256 checksums.
+    async fn read_applied_migrations(
         &self,
         db: &Client,
-    ) -> Result<Vec<AppliedMigration>, tokio_postgres::Error> {
+    ) -> Result<Vec<AppliedMigration>, MigrationError> {
+        match self.get_applied_migrations(db).await {
+            Err(MigrationError::PostgresError(e))
+                if e.code() == Some(&SqlState::UNDEFINED_TABLE) =>
+            {
+                Ok(vec![])
+            }
+            Err(MigrationError::PostgresError(e))
+                if e.code() == Some(&SqlState::UNDEFINED_COLUMN) =>
+            {
+                self.select_applied_migrations(db, "'sha256'").await
+            }
+            result => result,
+        }
+    }
+
+    async fn select_applied_migrations<C: GenericClient>(
+        &self,
+        db: &C,
+        checksum_algo: &str,
+    ) -> Result<Vec<AppliedMigration>, MigrationError> {
         let mut result: Vec<AppliedMigration> = vec![];
 
         db.query(
-            r#"
-                SELECT version, checksum
-                FROM migrations
-                ORDER BY version
-            "#,
+            &format!(
+                r#"
+                    SELECT version, name, checksum, {} AS checksum_algo, created_at
+                    FROM {}
+                    ORDER BY version
+                "#,
+                checksum_algo,
+                self.table()?
+            ),
             &[],
         )
         .await?
         .iter()
-        .try_for_each(|row: &Row| -> Result<(), tokio_postgres::Error> {
+        .try_for_each(|row: &Row| -> Result<(), MigrationError> {
             result.push(AppliedMigration {
                 checksum: row.try_get("checksum")?,
+                checksum_algo: row.try_get::<_, &str>("checksum_algo")?.parse()?,
+                created_at: row.try_get("created_at")?,
+                name: row.try_get("name")?,
                 version: row.try_get("version")?,
             });
 
@@ -178,24 +1336,392 @@ impl Migrator {
         &self,
         db: &mut Client,
         migration: &Migration,
-    ) -> Result<(), tokio_postgres::Error> {
+    ) -> Result<(), MigrationError> {
+        if !migration.transactional {
+            return self.apply_without_transaction(db, migration).await;
+        }
+
         let tx = db.transaction().await?;
+        self.run_migration(&tx, migration).await?;
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    /// Runs the statements of a migration one by one outside of a transaction,
+    /// storing a checkpoint after each so a later run can skip them.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            skip_all,
+            fields(version = migration.version, name = %migration.name)
+        )
+    )]
+    async fn apply_without_transaction(
+        &self,
+        db: &mut Client,
+        migration: &Migration,
+    ) -> Result<(), MigrationError> {
+        let checkpoints = self.checkpoint_table()?;
+
+        db.batch_execute(&format!(
+            r#"
+                CREATE TABLE IF NOT EXISTS {table} (
+                    version          BIGINT NOT NULL,
+                    statement_index  BIGINT NOT NULL,
+                    PRIMARY KEY (version, statement_index)
+                );
+
+                ALTER TABLE {table}
+                ADD COLUMN IF NOT EXISTS checksum VARCHAR(64);
+            "#,
+            table = checkpoints
+        ))
+        .await?;
+
+        // Checkpoints of an edited migration refer to statements that may no
+        // longer exist at the same index, so the migration starts over.
+        db.execute(
+            &format!(
+                "DELETE FROM {} WHERE version = $1 AND checksum IS DISTINCT FROM $2",
+                checkpoints
+            ),
+            &[&migration.version, &migration.checksum],
+        )
+        .await?;
+
+        let done = db
+            .query(
+                &format!(
+                    "SELECT statement_index FROM {} WHERE version = $1",
+                    checkpoints
+                ),
+                &[&migration.version],
+            )
+            .await?
+            .iter()
+            .map(|row| row.try_get::<_, i64>(0))
+            .collect::<Result<HashSet<_>, _>>()?;
+
+        let insert = format!(
+            "INSERT INTO {} (version, statement_index, checksum) VALUES ($1, $2, $3)",
+            checkpoints
+        );
+
+        let previous_timeout: Option<String> = match self.statement_timeout {
+            Some(timeout) => {
+                let row = db
+                    .query_one("SELECT current_setting('statement_timeout')", &[])
+                    .await?;
+
+                db.batch_execute(&format!("SET statement_timeout = {}", timeout.as_millis()))
+                    .await?;
+
+                Some(row.try_get(0)?)
+            }
+            None => None,
+        };
+
+        let result = async {
+            for (i, stmt) in migration
+                .statements_with(self.delimiter)
+                .into_iter()
+                .enumerate()
+            {
+                let index = i as i64 + 1;
+
+                if done.contains(&index) {
+                    continue;
+                }
 
-        for stmt in migration.sql.split(";") {
-            if !stmt.trim().is_empty() {
-                tx.execute(&stmt.to_string(), &[]).await?;
+                self.run_statement(&mut Conn(&*db), migration, i, stmt)
+                    .await?;
+                db.execute(&insert, &[&migration.version, &index, &migration.checksum])
+                    .await?;
             }
+
+            Ok::<_, MigrationError>(())
         }
+        .await;
+
+        let restored = match &previous_timeout {
+            Some(timeout) => db
+                .execute(
+                    "SELECT set_config('statement_timeout', $1, false)",
+                    &[timeout],
+                )
+                .await
+                .map(drop),
+            None => Ok(()),
+        };
 
+        result?;
+        restored?;
+
+        let tx = db.transaction().await?;
+        self.record_migration(&tx, migration).await?;
         tx.execute(
-            r#"
-                INSERT INTO migrations ( version, name, checksum )
-                VALUES ($1, $2, $3)
-            "#,
-            &[&migration.version, &migration.name, &migration.checksum],
+            &format!("DELETE FROM {} WHERE version = $1", checkpoints),
+            &[&migration.version],
+        )
+        .await?;
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            skip_all,
+            fields(version = migration.version, name = %migration.name)
+        )
+    )]
+    async fn run_migration<C: GenericClient>(
+        &self,
+        db: &C,
+        migration: &Migration,
+    ) -> Result<(), MigrationError> {
+        if let Some(timeout) = self.statement_timeout {
+            db.batch_execute(&format!(
+                "SET LOCAL statement_timeout = {}",
+                timeout.as_millis()
+            ))
+            .await?;
+        }
+
+        self.run_statements(&mut Conn(db), migration).await?;
+        self.record_migration(db, migration).await?;
+
+        Ok(())
+    }
+
+    async fn run_statements<E: execute::Execute>(
+        &self,
+        target: &mut E,
+        migration: &Migration,
+    ) -> Result<(), MigrationError> {
+        for (i, stmt) in migration
+            .statements_with(self.delimiter)
+            .into_iter()
+            .enumerate()
+        {
+            self.run_statement(target, migration, i, stmt).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn run_statement<E: execute::Execute>(
+        &self,
+        target: &mut E,
+        migration: &Migration,
+        i: usize,
+        stmt: &str,
+    ) -> Result<(), MigrationError> {
+        let result = match self.delimiter {
+            StatementDelimiter::Semicolon => target.execute(stmt).await,
+            StatementDelimiter::GoBatch => target.batch_execute(stmt).await,
+        };
+
+        result.map_err(|e| match e {
+            MigrationError::PostgresError(source) => MigrationError::ApplyError {
+                version: migration.version,
+                statement_index: i + 1,
+                source,
+            },
+            e => e,
+        })
+    }
+
+    async fn record_migration<C: GenericClient>(
+        &self,
+        db: &C,
+        migration: &Migration,
+    ) -> Result<(), MigrationError> {
+        db.execute(
+            &format!(
+                r#"
+                    INSERT INTO {} ( version, name, checksum, checksum_algo, labels )
+                    VALUES ($1, $2, $3, $4, $5)
+                "#,
+                self.table()?
+            ),
+            &[
+                &migration.version,
+                &migration.name,
+                &migration.checksum_with(self.checksum_algo),
+                &self.checksum_algo.as_str(),
+                &migration.labels,
+            ],
         )
         .await?;
 
-        tx.commit().await
+        Ok(())
+    }
+}
+
+/// Joins the SQL of `pending`, each preceded by a comment naming it.
+fn render_plan(pending: &[&Migration]) -> String {
+    let mut plan = String::new();
+
+    for migration in pending {
+        if !plan.is_empty() {
+            plan.push('\n');
+        }
+
+        plan.push_str(&format!("-- migration {}\n", migration.display_id()));
+        plan.push_str(migration.sql.trim_end());
+        plan.push('\n');
+    }
+
+    plan
+}
+
+/// Validates a possibly schema-qualified table name and quotes its parts.
+fn quote_table_name(name: &str) -> Result<String, MigrationError> {
+    if !TABLE_NAME_REGEX.is_match(name) {
+        return Err(MigrationError::TableNameError(name.to_owned()));
+    }
+
+    Ok(name
+        .split('.')
+        .map(quote_ident)
+        .collect::<Vec<_>>()
+        .join("."))
+}
+
+/// Wraps an identifier in double quotes, doubling any embedded quotes.
+fn quote_ident(ident: &str) -> String {
+    format!("\"{}\"", ident.replace('"', "\"\""))
+}
+
+#[cfg(test)]
+fn applied(migration: &Migration) -> AppliedMigration {
+    AppliedMigration {
+        checksum: migration.checksum.clone(),
+        checksum_algo: ChecksumAlgo::Sha256,
+        created_at: SystemTime::now(),
+        name: migration.name.clone(),
+        version: migration.version,
+    }
+}
+
+#[test]
+fn select_pending() {
+    let migrator = Migrator::new(
+        (1..=1000)
+            .map(|v| Migration::new(v, "step".into(), format!("SELECT {};", v)))
+            .collect(),
+    );
+
+    let current = migrator.migrations[..900]
+        .iter()
+        .map(applied)
+        .collect::<Vec<_>>();
+    let pending = migrator.select_pending(&current).unwrap();
+
+    assert_eq!(100, pending.len());
+    assert_eq!(901, pending[0].version);
+    assert_eq!(1000, pending[99].version);
+
+    let current = migrator.migrations.iter().map(applied).collect::<Vec<_>>();
+    assert!(migrator.select_pending(&current).unwrap().is_empty());
+}
+
+#[test]
+fn select_pending_rejects_changes() {
+    let migrator = Migrator::new(vec![
+        Migration::new(1, "first".into(), "SELECT 1;".into()),
+        Migration::new(2, "second".into(), "SELECT 2;".into()),
+        Migration::new(3, "third".into(), "SELECT 3;".into()),
+    ]);
+
+    let mut current = vec![
+        applied(&migrator.migrations[0]),
+        applied(&migrator.migrations[2]),
+    ];
+    assert!(matches!(
+        migrator.select_pending(&current),
+        Err(MigrationError::OutOfOrderMigration(2))
+    ));
+
+    let migrator = migrator.allow_out_of_order(true);
+    let pending = migrator.select_pending(&current).unwrap();
+    assert_eq!(1, pending.len());
+    assert_eq!(2, pending[0].version);
+
+    current[0].checksum = "changed".into();
+    assert!(matches!(
+        migrator.select_pending(&current),
+        Err(MigrationError::ChecksumError)
+    ));
+
+    let migrator = migrator.on_checksum_mismatch(MismatchPolicy::Warn);
+    assert_eq!(1, migrator.select_pending(&current).unwrap().len());
+}
+
+#[test]
+fn table_name() {
+    let migrator = Migrator::new(vec![]);
+    assert_eq!("\"migrations\"", migrator.table().unwrap());
+
+    let migrator = migrator.table_name("order");
+    assert_eq!("\"order\"", migrator.table().unwrap());
+
+    let migrator = migrator.table_name("app.user");
+    assert_eq!("\"app\".\"user\"", migrator.table().unwrap());
+
+    for name in ["", "a\"; DROP TABLE b; --", "a.b.c", "1a"] {
+        assert!(matches!(
+            Migrator::new(vec![]).table_name(name).validate(),
+            Err(MigrationError::TableNameError(_))
+        ));
     }
+
+    assert_eq!("\"a\"\"b\"", quote_ident("a\"b"));
+}
+
+#[test]
+fn plan_sql() {
+    let first = Migration::new(1, "first".into(), "CREATE TABLE a (id INT);\n\n".into());
+    let second = Migration::new(2, "second".into(), "DROP TABLE a;".into());
+
+    assert_eq!(
+        "-- migration 0001_first\nCREATE TABLE a (id INT);\n\n-- migration 0002_second\nDROP TABLE a;\n",
+        render_plan(&[&first, &second])
+    );
+    assert_eq!("", render_plan(&[]));
+}
+
+#[test]
+fn template_vars() {
+    let lookup = |name: &str| (name == "SCHEMA").then(|| "app".to_owned());
+
+    assert_eq!(
+        "CREATE TABLE app.users (id INT); SELECT $1, $body$ ${not a var} $body$;",
+        substitute_vars(
+            "CREATE TABLE ${SCHEMA}.users (id INT); SELECT $1, $body$ ${not a var} $body$;",
+            lookup
+        )
+        .unwrap()
+    );
+    assert_eq!(
+        "SELECT '${SCHEMA}', 'app'",
+        substitute_vars("SELECT '$${SCHEMA}', '${SCHEMA}'", lookup).unwrap()
+    );
+    assert!(matches!(
+        substitute_vars("SELECT '${MISSING}'", lookup),
+        Err(MigrationError::UndefinedVariable(name)) if name == "MISSING"
+    ));
+}
+
+#[test]
+fn var_names() {
+    assert_eq!(
+        vec!["SCHEMA", "OWNER"],
+        template_var_names(
+            "CREATE TABLE ${SCHEMA}.a (id INT); ALTER TABLE ${SCHEMA}.a OWNER TO ${OWNER}; SELECT '$${ESCAPED}', $1;"
+        )
+    );
 }
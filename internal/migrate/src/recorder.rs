@@ -0,0 +1,45 @@
+use crate::{Execute, MigrationError, Migrator};
+
+/// Captures executed migration SQL instead of running it, for testing a
+/// migration set without a database.
+#[derive(Debug, Default)]
+pub struct Recorder {
+    statements: Vec<String>,
+}
+
+impl Recorder {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// The statements and batches executed so far, in order.
+    pub fn statements(&self) -> &[String] {
+        &self.statements
+    }
+}
+
+impl Execute for Recorder {
+    async fn execute(&mut self, sql: &str) -> Result<(), MigrationError> {
+        self.statements.push(sql.to_owned());
+        Ok(())
+    }
+
+    async fn batch_execute(&mut self, sql: &str) -> Result<(), MigrationError> {
+        self.statements.push(sql.to_owned());
+        Ok(())
+    }
+}
+
+impl Migrator {
+    /// Validates the migrations and executes all of them against `target`,
+    /// without consulting or updating the migrations table.
+    pub async fn apply_to<E: Execute>(&self, target: &mut E) -> Result<(), MigrationError> {
+        self.validate()?;
+
+        for migration in &self.migrations {
+            self.run_statements(target, migration).await?;
+        }
+
+        Ok(())
+    }
+}
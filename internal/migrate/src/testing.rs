@@ -0,0 +1,51 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use tokio_postgres::{Config, NoTls};
+
+use crate::{quote_ident, MigrationError, Migrator};
+
+impl Migrator {
+    /// Applies all migrations to a new, uniquely named database and drops it
+    /// again afterwards, also when a migration fails. This checks in e.g. CI
+    /// that the whole migration set applies cleanly from scratch.
+    ///
+    /// `admin_url` must connect as a user allowed to create databases. Both
+    /// connections are made without TLS.
+    pub async fn test_apply(&self, admin_url: &str) -> Result<(), MigrationError> {
+        let mut config: Config = admin_url.parse()?;
+
+        let (admin, connection) = config.connect(NoTls).await?;
+        tokio::spawn(connection);
+
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        let name = format!("pgutils_test_{}_{}", std::process::id(), nanos);
+
+        admin
+            .batch_execute(&format!("CREATE DATABASE {}", quote_ident(&name)))
+            .await?;
+
+        let result = async {
+            let (mut db, connection) = config.dbname(&name).connect(NoTls).await?;
+            let connection = tokio::spawn(connection);
+
+            let result = self.migrate(&mut db).await;
+
+            // The database can only be dropped once this connection is closed.
+            drop(db);
+            let _ = connection.await;
+
+            result
+        }
+        .await;
+
+        let dropped = admin
+            .batch_execute(&format!("DROP DATABASE {}", quote_ident(&name)))
+            .await;
+
+        result?;
+        Ok(dropped?)
+    }
+}
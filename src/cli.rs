@@ -0,0 +1,118 @@
+use thiserror::Error;
+use tokio_postgres::{Client, NoTls};
+
+use crate::migrate::{MigrationError, MigrationState, Migrator};
+
+const USAGE: &str = "Usage: <migrate|status|validate>";
+
+#[derive(Error, Debug)]
+pub enum CliError {
+    #[error("{0}")]
+    Usage(String),
+
+    #[error(transparent)]
+    MigrationError(#[from] MigrationError),
+
+    #[error(transparent)]
+    PostgresError(#[from] tokio_postgres::Error),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Command {
+    Migrate,
+    Status,
+    Validate,
+}
+
+impl Command {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, CliError> {
+        let command = match args.next().as_deref() {
+            Some("migrate") => Command::Migrate,
+            Some("status") => Command::Status,
+            Some("validate") => Command::Validate,
+            Some("rollback") => {
+                return Err(CliError::Usage(
+                    "rollback is not supported, migrations cannot be reverted".into(),
+                ))
+            }
+            _ => return Err(CliError::Usage(USAGE.into())),
+        };
+
+        match args.next() {
+            Some(arg) => Err(CliError::Usage(format!("Unexpected argument {:?}", arg))),
+            None => Ok(command),
+        }
+    }
+}
+
+/// Runs the subcommand given as the first program argument and prints the
+/// outcome, for a ready-made `cargo run -- migrate` entrypoint:
+///
+/// - `migrate` applies all pending migrations.
+/// - `status` lists every migration with its state.
+/// - `validate` checks the migrations without connecting to the database.
+///
+/// The connection to `db_url` is made without TLS.
+pub async fn run_cli(migrator: Migrator, db_url: &str) -> Result<(), CliError> {
+    match Command::parse(std::env::args().skip(1))? {
+        Command::Migrate => {
+            let mut db = connect(db_url).await?;
+            let pending = migrator
+                .status(&db)
+                .await?
+                .iter()
+                .filter(|s| s.state == MigrationState::Pending)
+                .count();
+
+            migrator.migrate(&mut db).await?;
+
+            match pending {
+                0 => println!("Already up to date"),
+                n => println!("Applied {} migration(s)", n),
+            }
+        }
+        Command::Status => {
+            let db = connect(db_url).await?;
+
+            for status in migrator.status(&db).await? {
+                let state = match status.state {
+                    MigrationState::Applied { .. } => "applied",
+                    MigrationState::Pending => "pending",
+                    MigrationState::Drifted => "drifted",
+                    MigrationState::Orphaned => "orphaned",
+                };
+
+                println!("{:>10}  {:<8}  {}", status.version, state, status.name);
+            }
+        }
+        Command::Validate => {
+            migrator.validate()?;
+            println!("{} migration(s) are valid", migrator.migrations.len());
+        }
+    }
+
+    Ok(())
+}
+
+async fn connect(db_url: &str) -> Result<Client, CliError> {
+    let (client, connection) = tokio_postgres::connect(db_url, NoTls).await?;
+    tokio::spawn(connection);
+
+    Ok(client)
+}
+
+#[test]
+fn parse_command() {
+    let parse = |args: &[&str]| Command::parse(args.iter().map(|a| a.to_string()));
+
+    assert_eq!(Command::Migrate, parse(&["migrate"]).unwrap());
+    assert_eq!(Command::Status, parse(&["status"]).unwrap());
+    assert_eq!(Command::Validate, parse(&["validate"]).unwrap());
+
+    assert!(matches!(parse(&[]), Err(CliError::Usage(_))));
+    assert!(matches!(parse(&["rollback"]), Err(CliError::Usage(_))));
+    assert!(matches!(
+        parse(&["status", "--all"]),
+        Err(CliError::Usage(_))
+    ));
+}
@@ -1,23 +1,175 @@
-use std::ops::Deref;
+use std::{future::Future, ops::Deref, time::Duration};
 
-use futures_util::{pin_mut, TryStreamExt};
-use qp_postgres::PgPool;
+use bytes::Bytes;
+use futures_util::{pin_mut, stream, Stream, TryStreamExt};
+use qp_postgres::{
+    qp::{async_trait, resource::Manage, Pool, Pooled},
+    PgPool,
+};
 use thiserror::Error;
 use tokio_postgres::{
+    config::SslMode,
+    error::SqlState,
     tls::{MakeTlsConnect, TlsConnect},
-    types::ToSql,
-    Row, Socket, ToStatement,
+    types::{ToSql, Type},
+    Client, Config, Row, Socket, Statement, ToStatement,
+};
+
+use crate::{
+    query::{Page, Paginated, Query, Upserted},
+    tls::{MakeTls, TlsMode},
 };
 
-use crate::query::Query;
+const DEFAULT_POOL_SIZE: usize = 10;
+
+/// A statement accepted by `Database` and `Transaction`, which reports its SQL
+/// for tracing. Prepared statements do not keep their SQL and report `None`.
+pub trait StatementSql: ToStatement {
+    fn sql(&self) -> Option<&str>;
+}
+
+impl StatementSql for str {
+    fn sql(&self) -> Option<&str> {
+        Some(self)
+    }
+}
+
+impl StatementSql for String {
+    fn sql(&self) -> Option<&str> {
+        Some(self)
+    }
+}
+
+impl StatementSql for Statement {
+    fn sql(&self) -> Option<&str> {
+        None
+    }
+}
+
+/// A connection checked out of the pool, which dereferences to the underlying
+/// `tokio_postgres::Client` and is returned to the pool when dropped.
+pub type PooledConnection<'a, P> = Pooled<'a, ConnectionManager<P>>;
+
+/// Creates the connections of a `Database` through the manager of the pool it
+/// was created with, running setup SQL such as `SET search_path` once on every
+/// new connection. The original pool itself is not used to hold connections.
+pub struct ConnectionManager<P>
+where
+    P: MakeTlsConnect<Socket> + Clone + Send + Sync,
+    P::Stream: Send + Sync + 'static,
+    P::TlsConnect: Send + Sync,
+    <P::TlsConnect as TlsConnect<Socket>>::Future: Send,
+{
+    pool: PgPool<P>,
+    setup: Option<String>,
+}
+
+#[async_trait]
+impl<P> Manage for ConnectionManager<P>
+where
+    P: MakeTlsConnect<Socket> + Clone + Send + Sync,
+    P::Stream: Send + Sync + 'static,
+    P::TlsConnect: Send + Sync,
+    <P::TlsConnect as TlsConnect<Socket>>::Future: Send,
+{
+    type Output = Client;
+    type Error = tokio_postgres::Error;
+
+    async fn try_create(&self) -> Result<Client, Self::Error> {
+        let client = self.pool.manager().try_create().await?;
+
+        if let Some(setup) = &self.setup {
+            client.batch_execute(setup).await?;
+        }
+
+        Ok(client)
+    }
+
+    async fn validate(&self, client: &Client) -> bool {
+        self.pool.manager().validate(client).await
+    }
+}
 
 #[derive(Error, Debug)]
 pub enum DatabaseError {
     #[error("Query returned an unexpected number of rows")]
     EmptyResult,
 
+    #[error("Timed out while acquiring a connection from the pool")]
+    PoolTimeout,
+
+    #[error("Failed to set up TLS: {0}")]
+    TlsError(Box<dyn std::error::Error + Send + Sync>),
+
+    /// A unique constraint was violated, see `DatabaseError::constraint_name`
+    /// and `DatabaseError::conflict_detail`.
     #[error(transparent)]
-    PostgresError(#[from] tokio_postgres::Error),
+    UniqueViolation(tokio_postgres::Error),
+
+    #[error(transparent)]
+    PostgresError(tokio_postgres::Error),
+}
+
+impl DatabaseError {
+    /// Returns the name of the violated constraint for `UniqueViolation`, e.g.
+    /// to tell which field of a form is already taken.
+    pub fn constraint_name(&self) -> Option<&str> {
+        match self {
+            DatabaseError::UniqueViolation(e) => e.as_db_error()?.constraint(),
+            _ => None,
+        }
+    }
+
+    /// Returns the server's description of the conflicting key for
+    /// `UniqueViolation`, e.g. `Key (email)=(a@example.com) already exists.`
+    /// The value is only included if the user may read it.
+    pub fn conflict_detail(&self) -> Option<&str> {
+        match self {
+            DatabaseError::UniqueViolation(e) => e.as_db_error()?.detail(),
+            _ => None,
+        }
+    }
+}
+
+impl From<tokio_postgres::Error> for DatabaseError {
+    fn from(e: tokio_postgres::Error) -> Self {
+        if e.code() == Some(&SqlState::UNIQUE_VIOLATION) {
+            DatabaseError::UniqueViolation(e)
+        } else {
+            DatabaseError::PostgresError(e)
+        }
+    }
+}
+
+/// Maps a row to a value, for use with `Database::query_one_as` and
+/// `Query::get_one_as`.
+pub trait FromRow: Sized {
+    fn from_row(row: &Row) -> Result<Self, tokio_postgres::Error>;
+}
+
+/// Version of the server, as returned by `Database::server_version`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ServerVersion {
+    pub major: i32,
+    pub minor: i32,
+}
+
+impl ServerVersion {
+    /// Parses `server_version_num`, e.g. `150004` for 15.4. Before 10, the
+    /// major version had two parts, so `90624` (9.6.24) becomes 9.6.
+    fn from_num(num: i32) -> Self {
+        if num >= 100_000 {
+            Self {
+                major: num / 10_000,
+                minor: num % 10_000,
+            }
+        } else {
+            Self {
+                major: num / 10_000,
+                minor: num / 100 % 100,
+            }
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -28,7 +180,30 @@ where
     P::TlsConnect: Send + Sync,
     <P::TlsConnect as TlsConnect<Socket>>::Future: Send,
 {
-    pool: PgPool<P>,
+    pool: Pool<ConnectionManager<P>>,
+    acquire_timeout: Option<Duration>,
+    search_path: Option<String>,
+    on_connect: Option<String>,
+}
+
+impl Database<MakeTls> {
+    /// Creates a database with a default-sized pool from a connection string
+    /// such as `host=localhost user=postgres` or `postgresql://...`.
+    pub fn connect(config: &str, tls: TlsMode) -> Result<Self, DatabaseError> {
+        let mut config: Config = config.parse()?;
+
+        if tls == TlsMode::NoTls && config.get_ssl_mode() == SslMode::Prefer {
+            config.ssl_mode(SslMode::Disable);
+        }
+
+        let tls = MakeTls::new(tls)?;
+
+        Ok(Self::new(qp_postgres::connect(
+            config,
+            tls,
+            DEFAULT_POOL_SIZE,
+        )))
+    }
 }
 
 impl<P> Database<P>
@@ -39,7 +214,98 @@ where
     <P::TlsConnect as TlsConnect<Socket>>::Future: Send,
 {
     pub fn new(pool: PgPool<P>) -> Self {
-        Self { pool }
+        let max_size = pool.max_size();
+        let manager = ConnectionManager { pool, setup: None };
+
+        Self {
+            pool: Pool::new(manager, max_size),
+            acquire_timeout: None,
+            search_path: None,
+            on_connect: None,
+        }
+    }
+
+    /// Limits how long to wait for a free connection when the pool is
+    /// exhausted. Exceeding it fails with `DatabaseError::PoolTimeout`.
+    pub fn acquire_timeout(mut self, timeout: Duration) -> Self {
+        self.acquire_timeout = Some(timeout);
+        self
+    }
+
+    /// Sets the `search_path` of every connection when the pool opens it, so
+    /// unqualified names resolve the same way on all pooled connections. The
+    /// path is inserted into the `SET` statement verbatim and must be trusted,
+    /// e.g. `app, public`. Call this before acquiring connections, as the pool
+    /// is replaced.
+    pub fn with_search_path(mut self, path: &str) -> Self {
+        self.search_path = Some(format!("SET search_path TO {}", path));
+        self.rebuild_pool();
+        self
+    }
+
+    /// Runs `sql` once on every new connection when the pool opens it, e.g.
+    /// to set the time zone or `application_name` for the whole session. The
+    /// statements run before the one of `with_search_path`. Call this before
+    /// acquiring connections, as the pool is replaced.
+    pub fn on_connect(mut self, sql: Vec<String>) -> Self {
+        self.on_connect = (!sql.is_empty()).then(|| sql.join(";\n"));
+        self.rebuild_pool();
+        self
+    }
+
+    /// Checks a connection out of the pool for direct use of tokio-postgres.
+    /// Session state left on the connection, such as settings changed with
+    /// `SET`, carries over to later users of the pool.
+    pub async fn acquire(&self) -> Result<PooledConnection<'_, P>, DatabaseError> {
+        match self.acquire_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, self.pool.acquire())
+                .await
+                .map_err(|_| DatabaseError::PoolTimeout)?
+                .map_err(DatabaseError::from),
+            None => Ok(self.pool.acquire().await?),
+        }
+    }
+
+    /// Replaces the pool with one whose connections run the current setup SQL.
+    fn rebuild_pool(&mut self) {
+        let setup = [&self.on_connect, &self.search_path]
+            .into_iter()
+            .flatten()
+            .cloned()
+            .collect::<Vec<_>>();
+
+        let manager = ConnectionManager {
+            pool: self.pool.manager().pool.clone(),
+            setup: (!setup.is_empty()).then(|| setup.join(";\n")),
+        };
+
+        self.pool = Pool::new(manager, self.pool.max_size());
+    }
+
+    /// Opens up to `count` connections ahead of time, so the first requests
+    /// after startup don't each pay for connecting. The pool connects lazily
+    /// otherwise. `count` is capped at the pool size.
+    pub async fn warm_up(&self, count: usize) -> Result<(), DatabaseError> {
+        let count = count.min(self.pool.max_size());
+        let mut conns = Vec::with_capacity(count);
+
+        // Hold every connection until the end, or the pool would hand out the
+        // same one again.
+        for _ in 0..count {
+            conns.push(self.acquire().await?);
+        }
+
+        Ok(())
+    }
+
+    /// Returns the version of the server, e.g. to check for features like
+    /// `MERGE` which requires at least 15.
+    pub async fn server_version(&self) -> Result<ServerVersion, DatabaseError> {
+        let row = self
+            .query_one("SELECT current_setting('server_version_num')::int", &[])
+            .await?;
+
+        Ok(ServerVersion::from_num(row.try_get(0)?))
     }
 
     pub async fn query<T>(
@@ -48,17 +314,12 @@ where
         params: &[&(dyn ToSql + Sync)],
     ) -> Result<impl Iterator<Item = Row>, DatabaseError>
     where
-        T: ?Sized + ToStatement,
+        T: ?Sized + StatementSql,
     {
-        Ok(self
-            .pool
-            .acquire()
-            .await?
-            .query_raw(statement, slice_iter(params))
-            .await?
-            .try_collect::<Vec<Row>>()
-            .await?
-            .into_iter())
+        let conn = self.acquire().await?;
+        let rows = traced(statement.sql(), query_rows(&conn, statement, params)).await?;
+
+        Ok(rows.into_iter())
     }
 
     pub async fn query_one<T>(
@@ -67,27 +328,329 @@ where
         params: &[&(dyn ToSql + Sync)],
     ) -> Result<Row, DatabaseError>
     where
-        T: ?Sized + ToStatement,
+        T: ?Sized + StatementSql,
+    {
+        let conn = self.acquire().await?;
+        traced(statement.sql(), query_single(&conn, statement, params)).await
+    }
+
+    /// Fetches exactly one row like `Database::query_one` and maps it with
+    /// `FromRow`.
+    pub async fn query_one_as<R, T>(
+        &self,
+        statement: &T,
+        params: &[&(dyn ToSql + Sync)],
+    ) -> Result<R, DatabaseError>
+    where
+        R: FromRow,
+        T: ?Sized + StatementSql,
+    {
+        Ok(R::from_row(&self.query_one(statement, params).await?)?)
+    }
+
+    pub async fn execute<T>(
+        &self,
+        statement: &T,
+        params: &[&(dyn ToSql + Sync)],
+    ) -> Result<u64, DatabaseError>
+    where
+        T: ?Sized + StatementSql,
     {
-        let stream = self
-            .pool
-            .acquire()
+        let conn = self.acquire().await?;
+        traced(statement.sql(), execute_on(&conn, statement, params)).await
+    }
+
+    /// Runs `statement` once for every set of parameters and returns the total
+    /// number of affected rows. The statement is prepared once and all runs
+    /// share one connection. They are not wrapped in a transaction, so on
+    /// failure the runs before it remain applied.
+    pub async fn execute_many(
+        &self,
+        statement: &str,
+        param_sets: Vec<Vec<Box<dyn ToSql + Sync>>>,
+    ) -> Result<u64, DatabaseError> {
+        let conn = self.acquire().await?;
+
+        traced(Some(statement), async {
+            let statement = conn.prepare(statement).await?;
+            let mut total = 0;
+
+            for params in &param_sets {
+                total += conn
+                    .execute_raw(&statement, params.iter().map(Deref::deref))
+                    .await?;
+            }
+
+            Ok(total)
+        })
+        .await
+    }
+
+    /// Runs a write with a `RETURNING` clause (e.g. `INSERT ... RETURNING id`)
+    /// and collects the returned rows.
+    pub async fn execute_returning<T>(
+        &self,
+        statement: &T,
+        params: &[&(dyn ToSql + Sync)],
+    ) -> Result<Vec<Row>, DatabaseError>
+    where
+        T: ?Sized + StatementSql,
+    {
+        let conn = self.acquire().await?;
+        traced(statement.sql(), query_rows(&conn, statement, params)).await
+    }
+
+    /// Like `Database::query`, but converts every row into a JSON object with
+    /// `json::row_to_json`.
+    #[cfg(feature = "with-serde_json-1")]
+    pub async fn query_json<T>(
+        &self,
+        statement: &T,
+        params: &[&(dyn ToSql + Sync)],
+    ) -> Result<Vec<serde_json::Value>, DatabaseError>
+    where
+        T: ?Sized + StatementSql,
+    {
+        self.query(statement, params)
             .await?
-            .query_raw(statement, slice_iter(params))
+            .map(|row| Ok(crate::json::row_to_json(&row)?))
+            .collect()
+    }
+
+    /// Returns the plan of `query` without running it, e.g. to check that a
+    /// query uses an index. `EXPLAIN (FORMAT JSON)` is prepended here, so pass
+    /// the plain query.
+    #[cfg(feature = "with-serde_json-1")]
+    pub async fn explain(
+        &self,
+        query: impl crate::query::IntoQuery,
+    ) -> Result<serde_json::Value, DatabaseError> {
+        Ok(explained(query).get_one(self).await?.try_get(0)?)
+    }
+
+    /// Like `Database::query`, but with the type of every parameter declared
+    /// explicitly instead of inferred by the server.
+    pub async fn query_typed(
+        &self,
+        statement: &str,
+        params: &[(&(dyn ToSql + Sync), Type)],
+    ) -> Result<impl Iterator<Item = Row>, DatabaseError> {
+        let conn = self.acquire().await?;
+        let rows = traced(Some(statement), async {
+            Ok(conn.query_typed(statement, params).await?)
+        })
+        .await?;
+
+        Ok(rows.into_iter())
+    }
+
+    /// Runs a `COPY ... TO STDOUT` statement and streams its output. The
+    /// connection stays checked out of the pool until the stream is dropped.
+    pub async fn copy_out<T>(
+        &self,
+        statement: &T,
+    ) -> Result<impl Stream<Item = Result<Bytes, DatabaseError>> + '_, DatabaseError>
+    where
+        T: ?Sized + ToStatement,
+    {
+        let conn = self.acquire().await?;
+        let copy = Box::pin(conn.copy_out(statement).await?);
+
+        Ok(stream::unfold(
+            (conn, copy),
+            |(conn, mut copy)| async move {
+                copy.try_next()
+                    .await
+                    .map_err(DatabaseError::from)
+                    .transpose()
+                    .map(|item| (item, (conn, copy)))
+            },
+        ))
+    }
+
+    /// Starts a transaction on a dedicated connection. It is rolled back if
+    /// dropped without calling `Transaction::commit`.
+    pub async fn begin(&self) -> Result<Transaction<'_, P>, DatabaseError> {
+        let session = Session::new(self.acquire().await?);
+        session.conn().batch_execute("BEGIN").await?;
+
+        Ok(Transaction { session })
+    }
+
+    /// Runs `f` while holding the session-level advisory lock `key`, waiting
+    /// until the lock is available. The lock is released afterwards, also when
+    /// `f` fails. Should `f` panic or the future be dropped, the connection
+    /// holding the lock is closed, which releases it as well.
+    ///
+    /// The lock is held by a dedicated connection, so a pool of size one
+    /// leaves no connection for queries made by `f`.
+    pub async fn with_advisory_lock<F, Fut, T>(&self, key: i64, f: F) -> Result<T, DatabaseError>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<T, DatabaseError>>,
+    {
+        let mut session = Session::new(self.acquire().await?);
+
+        session
+            .conn()
+            .execute("SELECT pg_advisory_lock($1)", &[&key])
             .await?;
 
-        pin_mut!(stream);
+        let result = f().await;
 
-        let row = match stream.try_next().await? {
-            Some(row) => row,
-            None => return Err(DatabaseError::EmptyResult),
+        session
+            .conn()
+            .execute("SELECT pg_advisory_unlock($1)", &[&key])
+            .await?;
+
+        session.release();
+        result
+    }
+
+    /// Streams the rows of a query through a server-side cursor, fetching
+    /// `batch` rows at a time. The cursor lives in a transaction on a single
+    /// connection, which is closed instead of returned to the pool if the
+    /// stream is dropped before it is exhausted.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `batch` is not positive.
+    pub async fn fetch_cursor(
+        &self,
+        statement: &str,
+        params: &[&(dyn ToSql + Sync)],
+        batch: i32,
+    ) -> Result<impl Stream<Item = Result<Row, DatabaseError>> + '_, DatabaseError> {
+        assert!(batch > 0, "batch size must be positive");
+
+        let cursor = Cursor {
+            session: Session::new(self.acquire().await?),
+            rows: vec![].into_iter(),
+            done: false,
         };
 
-        if stream.try_next().await?.is_some() {
-            return Err(DatabaseError::EmptyResult);
-        }
+        cursor.conn().batch_execute("BEGIN").await?;
+        cursor
+            .conn()
+            .execute_raw(
+                &format!("DECLARE {} NO SCROLL CURSOR FOR {}", CURSOR_NAME, statement),
+                slice_iter(params),
+            )
+            .await?;
+
+        let fetch = format!("FETCH {} FROM {}", batch, CURSOR_NAME);
+
+        Ok(stream::try_unfold(cursor, move |mut cursor| {
+            let fetch = fetch.clone();
+
+            async move {
+                loop {
+                    if let Some(row) = cursor.rows.next() {
+                        return Ok(Some((row, cursor)));
+                    }
 
-        Ok(row)
+                    if cursor.done {
+                        cursor.finish().await?;
+                        return Ok(None);
+                    }
+
+                    let rows = cursor.conn().query(&fetch, &[]).await?;
+                    cursor.done = rows.len() < batch as usize;
+                    cursor.rows = rows.into_iter();
+                }
+            }
+        }))
+    }
+}
+
+const CURSOR_NAME: &str = "pgutils_cursor";
+
+struct Cursor<'a, P>
+where
+    P: MakeTlsConnect<Socket> + Clone + Send + Sync,
+    P::Stream: Send + Sync + 'static,
+    P::TlsConnect: Send + Sync,
+    <P::TlsConnect as TlsConnect<Socket>>::Future: Send,
+{
+    session: Session<'a, P>,
+    rows: std::vec::IntoIter<Row>,
+    done: bool,
+}
+
+impl<P> Cursor<'_, P>
+where
+    P: MakeTlsConnect<Socket> + Clone + Send + Sync,
+    P::Stream: Send + Sync + 'static,
+    P::TlsConnect: Send + Sync,
+    <P::TlsConnect as TlsConnect<Socket>>::Future: Send,
+{
+    fn conn(&self) -> &Client {
+        self.session.conn()
+    }
+
+    async fn finish(&mut self) -> Result<(), DatabaseError> {
+        self.conn()
+            .batch_execute(&format!("CLOSE {}; COMMIT", CURSOR_NAME))
+            .await?;
+
+        // The transaction is closed, so the connection can safely go back.
+        self.session.release();
+        Ok(())
+    }
+}
+
+/// A transaction started with `Database::begin`. Its methods mirror those of
+/// `Database`, but run on the transaction's connection.
+///
+/// Dropping it without committing closes the connection, which makes the
+/// server roll the transaction back.
+pub struct Transaction<'a, P>
+where
+    P: MakeTlsConnect<Socket> + Clone + Send + Sync,
+    P::Stream: Send + Sync + 'static,
+    P::TlsConnect: Send + Sync,
+    <P::TlsConnect as TlsConnect<Socket>>::Future: Send,
+{
+    session: Session<'a, P>,
+}
+
+impl<P> Transaction<'_, P>
+where
+    P: MakeTlsConnect<Socket> + Clone + Send + Sync,
+    P::Stream: Send + Sync + 'static,
+    P::TlsConnect: Send + Sync,
+    <P::TlsConnect as TlsConnect<Socket>>::Future: Send,
+{
+    pub async fn query<T>(
+        &self,
+        statement: &T,
+        params: &[&(dyn ToSql + Sync)],
+    ) -> Result<impl Iterator<Item = Row>, DatabaseError>
+    where
+        T: ?Sized + StatementSql,
+    {
+        let rows = traced(
+            statement.sql(),
+            query_rows(self.session.conn(), statement, params),
+        )
+        .await?;
+
+        Ok(rows.into_iter())
+    }
+
+    pub async fn query_one<T>(
+        &self,
+        statement: &T,
+        params: &[&(dyn ToSql + Sync)],
+    ) -> Result<Row, DatabaseError>
+    where
+        T: ?Sized + StatementSql,
+    {
+        traced(
+            statement.sql(),
+            query_single(self.session.conn(), statement, params),
+        )
+        .await
     }
 
     pub async fn execute<T>(
@@ -96,15 +659,173 @@ where
         params: &[&(dyn ToSql + Sync)],
     ) -> Result<u64, DatabaseError>
     where
-        T: ?Sized + ToStatement,
+        T: ?Sized + StatementSql,
     {
-        Ok(self
-            .pool
-            .acquire()
-            .await?
-            .execute_raw(statement, slice_iter(params))
-            .await?)
+        traced(
+            statement.sql(),
+            execute_on(self.session.conn(), statement, params),
+        )
+        .await
+    }
+
+    /// Defers checking constraints until commit, so e.g. rows referencing each
+    /// other can be inserted in any order. Only constraints declared
+    /// `DEFERRABLE` are affected, others are still checked immediately.
+    pub async fn set_constraints_deferred(&self) -> Result<(), DatabaseError> {
+        self.session
+            .conn()
+            .batch_execute("SET CONSTRAINTS ALL DEFERRED")
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn commit(mut self) -> Result<(), DatabaseError> {
+        self.finish("COMMIT").await
+    }
+
+    pub async fn rollback(mut self) -> Result<(), DatabaseError> {
+        self.finish("ROLLBACK").await
+    }
+
+    async fn finish(&mut self, statement: &str) -> Result<(), DatabaseError> {
+        self.session.conn().batch_execute(statement).await?;
+
+        // The transaction is closed, so the connection can safely go back.
+        self.session.release();
+        Ok(())
+    }
+}
+
+/// A connection carrying session state, such as an open transaction or an
+/// advisory lock. Unless released, it is closed instead of returned to the
+/// pool when dropped, so the state cannot leak to the next user.
+struct Session<'a, P>
+where
+    P: MakeTlsConnect<Socket> + Clone + Send + Sync,
+    P::Stream: Send + Sync + 'static,
+    P::TlsConnect: Send + Sync,
+    <P::TlsConnect as TlsConnect<Socket>>::Future: Send,
+{
+    conn: Option<PooledConnection<'a, P>>,
+}
+
+impl<'a, P> Session<'a, P>
+where
+    P: MakeTlsConnect<Socket> + Clone + Send + Sync,
+    P::Stream: Send + Sync + 'static,
+    P::TlsConnect: Send + Sync,
+    <P::TlsConnect as TlsConnect<Socket>>::Future: Send,
+{
+    fn new(conn: PooledConnection<'a, P>) -> Self {
+        Self { conn: Some(conn) }
+    }
+
+    fn conn(&self) -> &Client {
+        self.conn.as_ref().unwrap()
+    }
+
+    fn release(&mut self) {
+        self.conn.take();
+    }
+}
+
+impl<P> Drop for Session<'_, P>
+where
+    P: MakeTlsConnect<Socket> + Clone + Send + Sync,
+    P::Stream: Send + Sync + 'static,
+    P::TlsConnect: Send + Sync,
+    <P::TlsConnect as TlsConnect<Socket>>::Future: Send,
+{
+    fn drop(&mut self) {
+        if let Some(conn) = self.conn.take() {
+            drop(Pooled::take(conn));
+        }
+    }
+}
+
+/// Awaits a database call, emitting a debug event with the statement and the
+/// elapsed time when the `tracing` feature is enabled. Connections are
+/// acquired before, so waiting for the pool is not included. The statement is
+/// unknown for prepared statements, which do not keep their SQL.
+async fn traced<F, T>(sql: Option<&str>, f: F) -> Result<T, DatabaseError>
+where
+    F: Future<Output = Result<T, DatabaseError>>,
+{
+    #[cfg(feature = "tracing")]
+    let start = std::time::Instant::now();
+
+    let result = f.await;
+
+    #[cfg(feature = "tracing")]
+    tracing::debug!(
+        sql,
+        elapsed = ?start.elapsed(),
+        success = result.is_ok(),
+        "statement executed"
+    );
+
+    #[cfg(not(feature = "tracing"))]
+    let _ = sql;
+
+    result
+}
+
+// Shared by `Database` and `Transaction`, which only differ in where the
+// connection comes from.
+async fn query_rows<T>(
+    conn: &Client,
+    statement: &T,
+    params: &[&(dyn ToSql + Sync)],
+) -> Result<Vec<Row>, DatabaseError>
+where
+    T: ?Sized + ToStatement,
+{
+    Ok(conn
+        .query_raw(statement, slice_iter(params))
+        .await?
+        .try_collect()
+        .await?)
+}
+
+async fn query_single<T>(
+    conn: &Client,
+    statement: &T,
+    params: &[&(dyn ToSql + Sync)],
+) -> Result<Row, DatabaseError>
+where
+    T: ?Sized + ToStatement,
+{
+    let stream = conn.query_raw(statement, slice_iter(params)).await?;
+
+    pin_mut!(stream);
+
+    let row = match stream.try_next().await? {
+        Some(row) => row,
+        None => return Err(DatabaseError::EmptyResult),
+    };
+
+    if stream.try_next().await?.is_some() {
+        return Err(DatabaseError::EmptyResult);
     }
+
+    Ok(row)
+}
+
+async fn execute_on<T>(
+    conn: &Client,
+    statement: &T,
+    params: &[&(dyn ToSql + Sync)],
+) -> Result<u64, DatabaseError>
+where
+    T: ?Sized + ToStatement,
+{
+    Ok(conn.execute_raw(statement, slice_iter(params)).await?)
+}
+
+#[cfg(feature = "with-serde_json-1")]
+fn explained(query: impl crate::query::IntoQuery) -> Query {
+    query.into_query().explain()
 }
 
 fn slice_iter<'a>(
@@ -121,15 +842,22 @@ impl Query {
         P::TlsConnect: Send + Sync,
         <P::TlsConnect as TlsConnect<Socket>>::Future: Send,
     {
-        Ok(db
-            .pool
-            .acquire()
-            .await?
-            .query_raw(&self.to_string(), self.into_args().iter().map(Deref::deref))
-            .await?
-            .try_collect::<Vec<Row>>()
-            .await?
-            .into_iter())
+        let sql = self.to_string();
+        let types = self.param_types();
+
+        let conn = db.acquire().await?;
+
+        traced(Some(&sql), async {
+            let statement = conn.prepare_typed(&sql, &types).await?;
+
+            Ok(conn
+                .query_raw(&statement, self.into_args().iter().map(Deref::deref))
+                .await?
+                .try_collect::<Vec<Row>>()
+                .await?
+                .into_iter())
+        })
+        .await
     }
 
     pub async fn get_one<P>(self, db: &Database<P>) -> Result<Row, DatabaseError>
@@ -139,25 +867,64 @@ impl Query {
         P::TlsConnect: Send + Sync,
         <P::TlsConnect as TlsConnect<Socket>>::Future: Send,
     {
-        let stream = db
-            .pool
-            .acquire()
-            .await?
-            .query_raw(&self.to_string(), self.into_args().iter().map(Deref::deref))
-            .await?;
+        let sql = self.to_string();
+        let types = self.param_types();
 
-        pin_mut!(stream);
+        let conn = db.acquire().await?;
 
-        let row = match stream.try_next().await? {
-            Some(row) => row,
-            None => return Err(DatabaseError::EmptyResult),
-        };
+        traced(Some(&sql), async {
+            let statement = conn.prepare_typed(&sql, &types).await?;
+            let stream = conn
+                .query_raw(&statement, self.into_args().iter().map(Deref::deref))
+                .await?;
 
-        if stream.try_next().await?.is_some() {
-            return Err(DatabaseError::EmptyResult);
-        }
+            pin_mut!(stream);
+
+            let row = match stream.try_next().await? {
+                Some(row) => row,
+                None => return Err(DatabaseError::EmptyResult),
+            };
+
+            if stream.try_next().await?.is_some() {
+                return Err(DatabaseError::EmptyResult);
+            }
 
-        Ok(row)
+            Ok(row)
+        })
+        .await
+    }
+
+    /// Fetches exactly one row like `Query::get_one` and maps it with
+    /// `FromRow`.
+    pub async fn get_one_as<R, P>(self, db: &Database<P>) -> Result<R, DatabaseError>
+    where
+        R: FromRow,
+        P: MakeTlsConnect<Socket> + Clone + Send + Sync,
+        P::Stream: Send + Sync + 'static,
+        P::TlsConnect: Send + Sync,
+        <P::TlsConnect as TlsConnect<Socket>>::Future: Send,
+    {
+        Ok(R::from_row(&self.get_one(db).await?)?)
+    }
+
+    /// Runs a single-row `INSERT ... ON CONFLICT ... DO UPDATE` with
+    /// `RETURNING *, (xmax = 0) AS inserted` appended, reporting whether the row
+    /// was inserted or updated.
+    ///
+    /// `xmax` is only zero for a freshly inserted row version. Conflicts
+    /// resolved with `DO NOTHING` return no row and fail with `EmptyResult`.
+    pub async fn upsert_returning<P>(self, db: &Database<P>) -> Result<Upserted, DatabaseError>
+    where
+        P: MakeTlsConnect<Socket> + Clone + Send + Sync,
+        P::Stream: Send + Sync + 'static,
+        P::TlsConnect: Send + Sync,
+        <P::TlsConnect as TlsConnect<Socket>>::Future: Send,
+    {
+        let row = self.returning_inserted().get_one(db).await?;
+        // Read by position, the table itself may have an `inserted` column.
+        let inserted = row.try_get(row.len() - 1)?;
+
+        Ok(Upserted { row, inserted })
     }
 
     pub async fn execute<P>(self, db: &Database<P>) -> Result<u64, DatabaseError>
@@ -167,11 +934,126 @@ impl Query {
         P::TlsConnect: Send + Sync,
         <P::TlsConnect as TlsConnect<Socket>>::Future: Send,
     {
-        Ok(db
-            .pool
-            .acquire()
-            .await?
-            .execute_raw(&self.to_string(), self.into_args().iter().map(Deref::deref))
-            .await?)
+        let sql = self.to_string();
+        let types = self.param_types();
+
+        let conn = db.acquire().await?;
+
+        traced(Some(&sql), async {
+            let statement = conn.prepare_typed(&sql, &types).await?;
+
+            Ok(conn
+                .execute_raw(&statement, self.into_args().iter().map(Deref::deref))
+                .await?)
+        })
+        .await
     }
+
+    /// Runs a write with a `RETURNING` clause and collects the returned rows.
+    pub async fn execute_returning<P>(self, db: &Database<P>) -> Result<Vec<Row>, DatabaseError>
+    where
+        P: MakeTlsConnect<Socket> + Clone + Send + Sync,
+        P::Stream: Send + Sync + 'static,
+        P::TlsConnect: Send + Sync,
+        <P::TlsConnect as TlsConnect<Socket>>::Future: Send,
+    {
+        let sql = self.to_string();
+        let types = self.param_types();
+
+        let conn = db.acquire().await?;
+
+        traced(Some(&sql), async {
+            let statement = conn.prepare_typed(&sql, &types).await?;
+
+            Ok(conn
+                .query_raw(&statement, self.into_args().iter().map(Deref::deref))
+                .await?
+                .try_collect::<Vec<Row>>()
+                .await?)
+        })
+        .await
+    }
+}
+
+impl Paginated {
+    pub async fn fetch<P>(self, db: &Database<P>) -> Result<Page, DatabaseError>
+    where
+        P: MakeTlsConnect<Socket> + Clone + Send + Sync,
+        P::Stream: Send + Sync + 'static,
+        P::TlsConnect: Send + Sync,
+        <P::TlsConnect as TlsConnect<Socket>>::Future: Send,
+    {
+        let offset = self.offset();
+        let (count_sql, page_sql) = self.sql();
+        let types = self.query.param_types();
+        let mut args = self.query.into_args();
+        let conn = db.acquire().await?;
+
+        let total: i64 = traced(Some(&count_sql), async {
+            let statement = conn.prepare_typed(&count_sql, &types).await?;
+            let stream = conn
+                .query_raw(&statement, args.iter().map(Deref::deref))
+                .await?;
+
+            pin_mut!(stream);
+
+            match stream.try_next().await? {
+                Some(row) => Ok(row.try_get(0)?),
+                None => Err(DatabaseError::EmptyResult),
+            }
+        })
+        .await?;
+
+        args.push(Box::new(self.per_page));
+        args.push(Box::new(offset));
+
+        let rows = traced(Some(&page_sql), async {
+            let statement = conn.prepare_typed(&page_sql, &types).await?;
+
+            Ok(conn
+                .query_raw(&statement, args.iter().map(Deref::deref))
+                .await?
+                .try_collect::<Vec<Row>>()
+                .await?)
+        })
+        .await?;
+
+        Ok(Page {
+            rows,
+            total,
+            page: self.page,
+            per_page: self.per_page,
+        })
+    }
+}
+
+#[test]
+fn server_version() {
+    assert_eq!(
+        ServerVersion {
+            major: 15,
+            minor: 4
+        },
+        ServerVersion::from_num(150004)
+    );
+    assert_eq!(
+        ServerVersion { major: 9, minor: 6 },
+        ServerVersion::from_num(90624)
+    );
+    assert!(ServerVersion::from_num(100001) > ServerVersion::from_num(90624));
+}
+
+#[cfg(feature = "with-serde_json-1")]
+#[test]
+fn explained_query() {
+    let query = Query::new(("SELECT * FROM users WHERE id = ?", 1));
+
+    assert_eq!(
+        explained(query).to_string(),
+        "EXPLAIN (FORMAT JSON) SELECT * FROM users WHERE id = $1"
+    );
+    assert_eq!(
+        explained(crate::query::Delete::from("users")).to_string(),
+        "EXPLAIN (FORMAT JSON) DELETE FROM users"
+    );
 }
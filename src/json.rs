@@ -0,0 +1,52 @@
+use serde_json::{Map, Value};
+use tokio_postgres::{
+    types::{FromSql, Type},
+    Error, Row,
+};
+
+/// Converts a row into a JSON object keyed by column name. NULLs become
+/// `Value::Null`.
+///
+/// Booleans, integers, floats, text, `json`/`jsonb` and one-dimensional arrays
+/// of those are supported; other column types fail with a conversion error.
+pub fn row_to_json(row: &Row) -> Result<Value, Error> {
+    let mut object = Map::with_capacity(row.len());
+
+    for (i, column) in row.columns().iter().enumerate() {
+        object.insert(column.name().to_owned(), column_to_json(row, i)?);
+    }
+
+    Ok(Value::Object(object))
+}
+
+fn column_to_json(row: &Row, i: usize) -> Result<Value, Error> {
+    match *row.columns()[i].type_() {
+        Type::BOOL => get::<bool>(row, i),
+        Type::INT2 => get::<i16>(row, i),
+        Type::INT4 => get::<i32>(row, i),
+        Type::INT8 => get::<i64>(row, i),
+        Type::OID => get::<u32>(row, i),
+        Type::FLOAT4 => get::<f32>(row, i),
+        Type::FLOAT8 => get::<f64>(row, i),
+        Type::JSON | Type::JSONB => get::<Value>(row, i),
+        Type::BOOL_ARRAY => get::<Vec<Option<bool>>>(row, i),
+        Type::INT2_ARRAY => get::<Vec<Option<i16>>>(row, i),
+        Type::INT4_ARRAY => get::<Vec<Option<i32>>>(row, i),
+        Type::INT8_ARRAY => get::<Vec<Option<i64>>>(row, i),
+        Type::FLOAT4_ARRAY => get::<Vec<Option<f32>>>(row, i),
+        Type::FLOAT8_ARRAY => get::<Vec<Option<f64>>>(row, i),
+        Type::JSON_ARRAY | Type::JSONB_ARRAY => get::<Vec<Option<Value>>>(row, i),
+        Type::TEXT_ARRAY | Type::VARCHAR_ARRAY | Type::BPCHAR_ARRAY | Type::NAME_ARRAY => {
+            get::<Vec<Option<String>>>(row, i)
+        }
+        // Text types, and an error for anything else.
+        _ => get::<String>(row, i),
+    }
+}
+
+fn get<'a, T>(row: &'a Row, i: usize) -> Result<Value, Error>
+where
+    T: FromSql<'a> + Into<Value>,
+{
+    Ok(row.try_get::<_, Option<T>>(i)?.into())
+}
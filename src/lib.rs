@@ -1,13 +1,22 @@
+#[cfg(feature = "cli")]
+pub mod cli;
 mod database;
 
+#[cfg(feature = "with-serde_json-1")]
+pub mod json;
 pub mod query;
+pub mod tls;
+pub mod types;
 
 pub mod migrate {
     pub use pgutils_macros::embed;
     pub use pgutils_migrate::*;
 }
 
-pub use database::{Database, DatabaseError};
+pub use database::{
+    ConnectionManager, Database, DatabaseError, FromRow, PooledConnection, ServerVersion,
+    StatementSql, Transaction,
+};
 
 #[macro_export]
 macro_rules! migrate {
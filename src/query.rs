@@ -1,11 +1,22 @@
 use std::fmt::{Display, Write};
 
-use tokio_postgres::types::ToSql;
+use thiserror::Error;
+use tokio_postgres::{
+    types::{ToSql, Type},
+    Row,
+};
+
+#[derive(Error, Debug)]
+pub enum QueryError {
+    #[error("Column {0:?} is not allowed for ordering")]
+    OrderNotAllowed(String),
+}
 
 #[derive(Default)]
 pub struct Query {
-    args: Vec<Box<dyn ToSql>>,
+    args: Vec<Box<dyn ToSql + Sync>>,
     arg_indexes: Vec<usize>,
+    arg_types: Vec<Option<Type>>,
     buffer: String,
     cursor: usize,
     separated: bool,
@@ -25,6 +36,61 @@ impl Query {
         Default::default()
     }
 
+    /// Creates a query from SQL that already uses `$n` placeholders and the
+    /// matching arguments. The SQL is taken as-is: `?` is not treated as a
+    /// placeholder and no renumbering is performed, so fragments with `?`
+    /// pushed afterwards will be numbered from `$1` again.
+    pub fn from_raw(sql: impl Into<String>, args: Vec<Box<dyn ToSql + Sync>>) -> Self {
+        let buffer = sql.into();
+
+        Self {
+            arg_types: args.iter().map(|_| None).collect(),
+            args,
+            cursor: buffer.len(),
+            buffer,
+            ..Default::default()
+        }
+    }
+
+    /// Creates `INSERT INTO target (cols) <select>`, copying the rows
+    /// returned by `select` into `target`.
+    pub fn insert_from_select(target: &str, cols: &[&str], select: Query) -> Self {
+        let mut query =
+            Query::new(format!("INSERT INTO {} ({})", target, cols.join(", ")).as_str());
+        query.push(select);
+        query
+    }
+
+    /// Creates `SELECT cols`, the same as pushing each column with `comma`, so
+    /// more columns can still be added that way.
+    pub fn select(cols: &[&str]) -> Self {
+        let mut query = Query::new("SELECT");
+
+        for col in cols {
+            query.comma(*col);
+        }
+
+        query
+    }
+
+    /// Creates `SELECT cols FROM table`.
+    pub fn select_from(cols: &[&str], table: &str) -> Self {
+        let mut query = Query::select(cols);
+        query.push("FROM").push(table);
+        query
+    }
+
+    /// Clears the query for reuse while keeping its allocations.
+    pub fn reset(&mut self) -> &mut Self {
+        self.args.clear();
+        self.arg_indexes.clear();
+        self.arg_types.clear();
+        self.buffer.clear();
+        self.cursor = 0;
+        self.separated = false;
+        self
+    }
+
     pub fn push<F>(&mut self, frag: F) -> &mut Self
     where
         F: Fragment,
@@ -43,29 +109,41 @@ impl Query {
     where
         F: Fragment,
     {
-        if self.separated {
-            self.append_buffer(" AND ");
-        } else if !self.buffer.is_empty() {
-            self.append_buffer(" ");
+        self.separate(" AND ", |query| frag.push_to_query(query));
+        self
+    }
+
+    /// Like `and`, but only if `cond` is true.
+    pub fn and_if<F>(&mut self, cond: bool, frag: F) -> &mut Self
+    where
+        F: Fragment,
+    {
+        if cond {
+            self.and(frag);
         }
 
-        frag.push_to_query(self);
-        self.separated = true;
         self
     }
 
-    pub fn comma<F>(&mut self, frag: F) -> &mut Self
+    /// Like `and` with the fragment returned by `g`, but only if `opt` is
+    /// `Some`, e.g. `and_some(filter.name, |name| ("name = ?", name))`.
+    pub fn and_some<T, G, F>(&mut self, opt: Option<T>, g: G) -> &mut Self
     where
+        G: FnOnce(T) -> F,
         F: Fragment,
     {
-        if self.separated {
-            self.append_buffer(",");
-        } else if !self.buffer.is_empty() {
-            self.append_buffer(" ");
+        if let Some(value) = opt {
+            self.and(g(value));
         }
 
-        frag.push_to_query(self);
-        self.separated = true;
+        self
+    }
+
+    pub fn comma<F>(&mut self, frag: F) -> &mut Self
+    where
+        F: Fragment,
+    {
+        self.separate(",", |query| frag.push_to_query(query));
         self
     }
 
@@ -73,186 +151,2147 @@ impl Query {
     where
         F: Fragment,
     {
-        if self.separated {
-            self.append_buffer(" OR ");
-        } else if !self.buffer.is_empty() {
-            self.append_buffer(" ");
+        self.separate(" OR ", |query| frag.push_to_query(query));
+        self
+    }
+
+    /// Appends `NOT (frag)`, separated like `and`. Use `or_not` within an `or`
+    /// chain.
+    pub fn not<F>(&mut self, frag: F) -> &mut Self
+    where
+        F: Fragment,
+    {
+        self.negate(" AND ", frag)
+    }
+
+    /// Appends `NOT (frag)`, separated like `or`.
+    pub fn or_not<F>(&mut self, frag: F) -> &mut Self
+    where
+        F: Fragment,
+    {
+        self.negate(" OR ", frag)
+    }
+
+    fn negate<F>(&mut self, sep: &str, frag: F) -> &mut Self
+    where
+        F: Fragment,
+    {
+        let group = Query::new(frag);
+
+        if !group.is_empty() {
+            self.separate(sep, |query| {
+                query.append_buffer("NOT (");
+                query.append_query(group);
+                query.append_buffer(")");
+            });
         }
 
-        frag.push_to_query(self);
-        self.separated = true;
         self
     }
 
-    pub fn into_args(self) -> Vec<Box<dyn ToSql>> {
-        self.args
+    /// Appends SQL verbatim, without treating `?` as a placeholder. This
+    /// bypasses argument tracking, so any `$n` placeholders in `sql` must be
+    /// numbered by the caller to match the query's arguments.
+    pub fn push_raw(&mut self, sql: &str) -> &mut Self {
+        self.separated = false;
+
+        if !self.buffer.is_empty() {
+            self.buffer.push(' ');
+        }
+
+        self.buffer.push_str(sql);
+        self.cursor = self.buffer.len();
+        self
     }
 
-    fn append_buffer(&mut self, query: &str) {
-        self.append_buffer_with_args(query, vec![]);
+    /// Appends the `Display` form of `value` as a literal rather than a
+    /// parameter, for places where parameters are not allowed, such as
+    /// `FETCH FIRST n ROWS ONLY`. Only use this with trusted values like
+    /// numbers, as the text is not escaped.
+    pub fn push_lit(&mut self, value: impl Display) -> &mut Self {
+        self.push_raw(&value.to_string())
     }
 
-    fn append_buffer_with_args(&mut self, query: &str, mut args: Vec<Box<dyn ToSql>>) {
-        for c in query.chars() {
-            if c == '?' {
-                self.arg_indexes.push(self.cursor);
-            } else {
-                self.buffer.push(c);
-                self.cursor += c.len_utf8();
-            }
-        }
+    /// Appends `now()`, the start time of the current transaction according to
+    /// the server's clock.
+    pub fn push_now(&mut self) -> &mut Self {
+        self.push("now()")
+    }
 
-        self.args.append(&mut args);
+    /// Appends `ts` bound as a parameter, e.g. to use a fixed time in tests
+    /// instead of `Query::push_now`.
+    #[cfg(feature = "with-chrono-0_4")]
+    pub fn push_timestamp(&mut self, ts: chrono::DateTime<chrono::Utc>) -> &mut Self {
+        self.push(("?", ts))
     }
-}
 
-impl Display for Query {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let mut c = 1;
+    /// Appends `prefix $n::ty` with `value` bound to `$n`, e.g.
+    /// `push_cast("WHERE id =", id, "uuid")`. The type is inserted verbatim and
+    /// must be trusted.
+    pub fn push_cast<T>(&mut self, prefix: &str, value: T, ty: &str) -> &mut Self
+    where
+        T: ToSql + Sync + 'static,
+    {
+        let sql = match prefix {
+            "" => format!("?::{}", ty),
+            _ => format!("{} ?::{}", prefix, ty),
+        };
 
-        for a in [&[0], self.arg_indexes.as_slice(), &[self.buffer.len()]]
-            .concat()
-            .windows(2)
-        {
-            if a[0] != 0 {
-                f.write_char('$')?;
-                f.write_str(&c.to_string())?;
-                c += 1;
-            }
+        self.push((sql.as_str(), value))
+    }
 
-            f.write_str(&self.buffer[a[0]..a[1]])?;
-        }
+    /// Appends `sql` with its `?` replaced by `$n::type_name[]` and all `values`
+    /// bound to `$n` as a single array, e.g. to pass many rows of a composite
+    /// type to a function in one go with
+    /// `push_composite_array("SELECT * FROM import_items(?)", items, "item")`.
+    /// The type name is inserted verbatim and must be trusted.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `sql` does not contain exactly one `?`.
+    pub fn push_composite_array<T>(
+        &mut self,
+        sql: &str,
+        values: Vec<T>,
+        type_name: &str,
+    ) -> &mut Self
+    where
+        Vec<T>: ToSql + Sync + 'static,
+    {
+        assert_eq!(sql.matches('?').count(), 1, "expected one `?` in {:?}", sql);
 
-        Ok(())
+        let sql = sql.replace('?', &format!("?::{}[]", type_name));
+        self.push((sql.as_str(), values))
     }
-}
 
-pub trait Fragment {
-    fn push_to_query(self, query: &mut Query);
-}
+    /// Appends a validated and double-quoted identifier, such as a column name
+    /// chosen at runtime. Schema-qualified names (`schema.table`) are allowed.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the identifier is not safe, see `quote_ident`.
+    pub fn push_ident(&mut self, ident: &str) -> &mut Self {
+        let quoted = quote_ident(ident).unwrap_or_else(|| panic!("unsafe identifier: {:?}", ident));
 
-impl Fragment for &str {
-    fn push_to_query(self, query: &mut Query) {
-        query.append_buffer(self);
+        self.push(quoted.as_str())
     }
-}
 
-impl<T: ToSql + 'static> Fragment for (&str, T) {
-    fn push_to_query(self, query: &mut Query) {
-        query.append_buffer_with_args(self.0, vec![Box::new(self.1)]);
+    /// Appends `FOR UPDATE`, locking the selected rows against concurrent
+    /// writes. Locks are held until the end of the transaction, so this only
+    /// has an effect inside one.
+    pub fn for_update(&mut self) -> &mut Self {
+        self.push("FOR UPDATE")
     }
-}
 
-impl<T: ToSql + 'static> Fragment for (&str, T, T) {
-    fn push_to_query(self, query: &mut Query) {
-        query.append_buffer_with_args(self.0, vec![Box::new(self.1), Box::new(self.2)]);
+    /// Appends `FOR UPDATE SKIP LOCKED`, which leaves out rows locked by
+    /// others, so that e.g. queue workers each pick distinct rows. Like
+    /// `for_update`, this needs a transaction.
+    pub fn for_update_skip_locked(&mut self) -> &mut Self {
+        self.push("FOR UPDATE SKIP LOCKED")
     }
-}
 
-impl<T: ToSql + 'static> Fragment for (&str, T, T, T) {
-    fn push_to_query(self, query: &mut Query) {
-        query.append_buffer_with_args(
-            self.0,
-            vec![Box::new(self.1), Box::new(self.2), Box::new(self.3)],
-        );
+    /// Appends `FOR SHARE`, locking the selected rows against concurrent
+    /// writes while still allowing other readers to lock them. Like
+    /// `for_update`, this needs a transaction.
+    pub fn for_share(&mut self) -> &mut Self {
+        self.push("FOR SHARE")
     }
-}
 
-impl<F> Fragment for F
-where
-    F: FnOnce(&mut Query),
-{
-    fn push_to_query(self, query: &mut Query) {
-        let separated = query.separated;
+    /// Prepends `EXPLAIN (FORMAT JSON)`, returning the planned execution of the
+    /// query as JSON instead of its rows. `Database::explain` does this itself.
+    pub fn explain(mut self) -> Self {
+        self.insert_buffer(0, "EXPLAIN (FORMAT JSON) ");
+        self
+    }
 
-        if separated {
-            query.separated = false;
-            query.append_buffer("(");
+    /// Like `Query::explain`, but also runs the query to report actual row
+    /// counts and timings. Side effects of the query take place as usual.
+    pub fn explain_analyze(mut self) -> Self {
+        self.insert_buffer(0, "EXPLAIN (ANALYZE, FORMAT JSON) ");
+        self
+    }
+
+    /// Appends the `RETURNING` clause of `Query::upsert_returning`, which ends
+    /// with whether the row was inserted.
+    pub(crate) fn returning_inserted(mut self) -> Self {
+        self.push_raw("RETURNING *, (xmax = 0) AS inserted");
+        self
+    }
+
+    /// Turns the query into a `SELECT DISTINCT`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the query does not start with `SELECT`.
+    pub fn distinct(&mut self) -> &mut Self {
+        self.insert_after_select(" DISTINCT");
+        self
+    }
+
+    /// Turns the query into a `SELECT DISTINCT ON (cols)`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the query does not start with `SELECT`.
+    pub fn distinct_on(&mut self, cols: &str) -> &mut Self {
+        self.insert_after_select(&format!(" DISTINCT ON ({})", cols));
+        self
+    }
+
+    /// Appends `col LIKE ?` with the pattern bound as a parameter, separated by
+    /// `AND` like `Query::and`.
+    pub fn like<'a, L>(&mut self, col: &str, pattern: L) -> &mut Self
+    where
+        L: Into<LikePattern<'a>>,
+    {
+        self.and((format!("{} LIKE ?", col).as_str(), pattern.into().render()))
+    }
+
+    /// Same as `Query::like`, but matches case-insensitively using `ILIKE`.
+    pub fn ilike<'a, L>(&mut self, col: &str, pattern: L) -> &mut Self
+    where
+        L: Into<LikePattern<'a>>,
+    {
+        self.and((format!("{} ILIKE ?", col).as_str(), pattern.into().render()))
+    }
+
+    /// Appends `(col1 ILIKE ? OR col2 ILIKE ? ...)` matching `term` anywhere
+    /// in any of the columns, separated by `AND` like `Query::and`. LIKE
+    /// metacharacters in `term` are escaped. The pattern is bound once per
+    /// column, as placeholders cannot be reused.
+    pub fn search(&mut self, cols: &[&str], term: &str) -> &mut Self {
+        let pattern = LikePattern::Contains(term).render();
+
+        self.and(|query: &mut Query| {
+            for col in cols {
+                query.or((format!("{} ILIKE ?", col).as_str(), pattern.clone()));
+            }
+        })
+    }
+
+    /// Appends `col @@ plainto_tsquery(config, query)` for full-text search,
+    /// with the text search configuration (e.g. `english`) and the query text
+    /// bound as parameters, separated by `AND` like `Query::and`.
+    pub fn text_search(&mut self, col: &str, query: &str, config: &str) -> &mut Self {
+        self.and((
+            format!("{} @@ plainto_tsquery(?::text::regconfig, ?)", col).as_str(),
+            config.to_owned(),
+            query.to_owned(),
+        ))
+    }
+
+    /// Appends `col BETWEEN ? AND ?` with both bounds bound as parameters,
+    /// separated by `AND` like `Query::and`.
+    pub fn between<T>(&mut self, col: &str, low: T, high: T) -> &mut Self
+    where
+        T: ToSql + Sync + 'static,
+    {
+        self.and((format!("{} BETWEEN ? AND ?", col).as_str(), low, high))
+    }
+
+    /// Same as `Query::between`, but using `NOT BETWEEN`.
+    pub fn not_between<T>(&mut self, col: &str, low: T, high: T) -> &mut Self
+    where
+        T: ToSql + Sync + 'static,
+    {
+        self.and((format!("{} NOT BETWEEN ? AND ?", col).as_str(), low, high))
+    }
+
+    /// Appends `col IS NULL`, separated by `AND` like `Query::and`.
+    pub fn is_null(&mut self, col: &str) -> &mut Self {
+        self.and(format!("{} IS NULL", col).as_str())
+    }
+
+    /// Appends `col IS NOT NULL`, separated by `AND` like `Query::and`.
+    pub fn is_not_null(&mut self, col: &str) -> &mut Self {
+        self.and(format!("{} IS NOT NULL", col).as_str())
+    }
+
+    /// Appends `ORDER BY requested dir` if `requested` is one of `allowed`, so
+    /// a sort column chosen by users can be passed in directly. Fails with
+    /// `QueryError::OrderNotAllowed` otherwise, leaving the query unchanged.
+    pub fn order_by_checked(
+        &mut self,
+        requested: &str,
+        allowed: &[&str],
+        dir: Order,
+    ) -> Result<&mut Self, QueryError> {
+        if !allowed.contains(&requested) {
+            return Err(QueryError::OrderNotAllowed(requested.to_owned()));
         }
 
-        (self)(query);
+        let dir = match dir {
+            Order::Asc => "ASC",
+            Order::Desc => "DESC",
+        };
 
-        if separated {
-            query.separated = true;
-            query.append_buffer(")");
+        Ok(self.push(format!("ORDER BY {} {}", requested, dir).as_str()))
+    }
+
+    /// Appends `LIMIT $n` with `n` bound if it is `Some`, and nothing
+    /// otherwise.
+    pub fn limit_opt(&mut self, n: Option<i64>) -> &mut Self {
+        if let Some(n) = n {
+            self.push(("LIMIT ?", n));
         }
+
+        self
     }
-}
 
-impl Fragment for Query {
-    fn push_to_query(mut self, query: &mut Query) {
-        query.args.append(&mut self.args);
+    /// Appends `OFFSET $n` with `n` bound if it is `Some`, and nothing
+    /// otherwise.
+    pub fn offset_opt(&mut self, n: Option<i64>) -> &mut Self {
+        if let Some(n) = n {
+            self.push(("OFFSET ?", n));
+        }
 
-        if query.separated {
-            query.append_buffer("(");
+        self
+    }
+
+    /// Prepares the query for fetching a single page of rows along with the
+    /// total number of rows. Pages start at 1.
+    pub fn paginate(self, page: i64, per_page: i64) -> Paginated {
+        Paginated {
+            query: self,
+            page,
+            per_page,
         }
+    }
 
-        query
-            .arg_indexes
-            .extend(self.arg_indexes.into_iter().map(|i| i + query.cursor));
+    pub fn into_args(self) -> Vec<Box<dyn ToSql + Sync>> {
+        self.args
+    }
 
-        query.cursor += self.cursor;
+    /// Returns the declared types of the arguments, up to the last argument
+    /// bound with `Typed`. Undeclared types are left to the server to infer.
+    pub fn param_types(&self) -> Vec<Type> {
+        let len = self
+            .arg_types
+            .iter()
+            .rposition(Option::is_some)
+            .map_or(0, |i| i + 1);
 
-        query.append_buffer(&self.buffer);
+        self.arg_types[..len]
+            .iter()
+            .map(|t| t.clone().unwrap_or(Type::UNKNOWN))
+            .collect()
+    }
 
-        if query.separated {
+    /// Renders the query and returns it together with its arguments, e.g. for
+    /// use with tokio-postgres APIs not wrapped by this crate.
+    pub fn into_sql_and_args(self) -> (String, Vec<Box<dyn ToSql + Sync>>) {
+        (self.to_string(), self.args)
+    }
+
+    /// Renders the query over multiple lines, starting a line at each major
+    /// clause and indenting subqueries. Meant for logs and test snapshots; use
+    /// `Display` for execution.
+    pub fn to_pretty_string(&self) -> String {
+        pretty(&self.to_string())
+    }
+
+    /// Appends `prefix (sub)`, e.g. `push_subquery("WHERE id IN", ids)`. The
+    /// result counts as a condition, so a following `and` or `or` separates.
+    pub fn push_subquery(&mut self, prefix: &str, sub: Query) -> &mut Self {
+        self.push(prefix);
+        self.append_buffer(" (");
+        self.append_query(sub);
+        self.append_buffer(")");
+        self.separated = true;
+        self
+    }
+
+    /// Appends `EXISTS (sub)`, separated like `and`. `sub` may refer to
+    /// columns of the outer query, e.g. to keep rows with at least one related
+    /// row, and its arguments are renumbered to follow the query's.
+    pub fn where_exists(&mut self, sub: Query) -> &mut Self {
+        self.exists("EXISTS (", sub)
+    }
+
+    /// Appends `NOT EXISTS (sub)`, see `Query::where_exists`.
+    pub fn where_not_exists(&mut self, sub: Query) -> &mut Self {
+        self.exists("NOT EXISTS (", sub)
+    }
+
+    fn exists(&mut self, keyword: &str, sub: Query) -> &mut Self {
+        self.separate(" AND ", |query| {
+            query.append_buffer(keyword);
+            query.append_query(sub);
             query.append_buffer(")");
-        }
+        });
+
+        self
     }
-}
 
-#[test]
-fn simple_query() {
-    let mut query = Query::new("SELECT");
-    query.comma("a");
-    query.comma("b");
-    query.comma("c");
-    query.push("FROM foobar WHERE");
-    query.and("foo = 'bar'");
-    query.and(("bar = ?", 1));
+    /// Appends `(VALUES (?, ?), ...) AS alias (col, ...)`, binding every cell
+    /// of `rows` as a parameter, e.g. to join a set of rows from the client
+    /// against a table.
+    ///
+    /// Each column is given with its type, which is declared for its
+    /// parameters, as the server would otherwise infer `text` for all of them.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `rows` is empty or a row does not have one value per column.
+    pub fn values_table(
+        &mut self,
+        alias: &str,
+        cols: &[(&str, Type)],
+        rows: Vec<Vec<Box<dyn ToSql + Sync>>>,
+    ) -> &mut Self {
+        assert!(!rows.is_empty(), "VALUES requires at least one row");
 
-    query.and(|q: &mut Query| {
-        q.or(("d = ?", 10));
-        q.or(("e != ?", 20));
-    });
+        let row_sql = format!("({})", vec!["?"; cols.len()].join(", "));
+        let names = cols.iter().map(|(name, _)| *name).collect::<Vec<_>>();
+        let sql = format!(
+            "(VALUES {}) AS {} ({})",
+            vec![row_sql.as_str(); rows.len()].join(", "),
+            alias,
+            names.join(", ")
+        );
 
-    assert_eq!(
-        query.to_string(),
-        "SELECT a,b,c FROM foobar WHERE foo = 'bar' AND bar = $1 AND ( d = $2 OR e != $3)"
-    );
-}
+        let mut args = Vec::with_capacity(rows.len() * cols.len());
 
-#[test]
-fn query_concatenation() {
-    let mut query_a = Query::empty();
+        for row in rows {
+            assert_eq!(row.len(), cols.len(), "row has the wrong number of values");
+            args.extend(row);
+        }
 
-    query_a.push(|query: &mut Query| {
-        query.or(("c = ?", 3));
-        query.or("d = 4");
-        query.or(("e = ?", 4));
-    });
+        let count = args.len();
+        self.push(RawFragment { sql, args });
 
-    let mut query_b = Query::new("SELECT * FROM test WHERE");
-    query_b.and(("a = ?", 1));
-    query_b.and(("b = ?", 2));
-    query_b.and(query_a);
+        let types = cols.iter().map(|(_, ty)| Some(ty.clone())).cycle();
+        let start = self.arg_types.len() - count;
 
-    assert_eq!(
-        query_b.to_string(),
-        "SELECT * FROM test WHERE a = $1 AND b = $2 AND (c = $3 OR d = 4 OR e = $4)"
-    );
+        for (arg_type, ty) in self.arg_types[start..].iter_mut().zip(types) {
+            *arg_type = ty;
+        }
 
-    let mut fields = Query::empty();
-    fields.comma("foo");
-    fields.comma("bar");
-    fields.comma(("? as foobar", 1000));
+        self
+    }
 
-    let mut query = Query::new("SELECT");
-    query.push(fields);
-    query.push("FROM my_table");
+    /// Appends one fragment per item of `items`, separated by `sep`, e.g. the
+    /// assignments of an `UPDATE ... SET`. `f` pushes each fragment onto an
+    /// empty query, and items for which it pushes nothing are skipped.
+    pub fn join_fragments<I, F>(&mut self, sep: &str, items: I, mut f: F) -> &mut Self
+    where
+        I: IntoIterator,
+        F: FnMut(&mut Query, I::Item),
+    {
+        let mut joined = Query::empty();
 
-    assert_eq!(
-        query.to_string(),
-        "SELECT foo,bar,$1 as foobar FROM my_table"
+        for item in items {
+            let mut part = Query::empty();
+            f(&mut part, item);
+
+            if part.is_empty() {
+                continue;
+            }
+
+            if !joined.is_empty() {
+                joined.append_buffer(sep);
+            }
+
+            joined.append_query(part);
+        }
+
+        if !joined.is_empty() {
+            self.push(joined);
+        }
+
+        self
+    }
+
+    /// Appends `WITH RECURSIVE name(columns) AS (anchor UNION ALL recursive)`,
+    /// to be followed by the query selecting from `name`.
+    pub fn with_recursive(
+        &mut self,
+        name: &str,
+        columns: &[&str],
+        anchor: Query,
+        recursive: Query,
+    ) -> &mut Self {
+        self.push("WITH RECURSIVE");
+        self.push(name);
+        self.append_buffer("(");
+        self.append_buffer(&columns.join(", "));
+        self.append_buffer(") AS (");
+        self.append_query(anchor);
+        self.append_buffer(" UNION ALL ");
+        self.append_query(recursive);
+        self.append_buffer(")");
+        self
+    }
+
+    /// Appends `EXCEPT other`, keeping the rows not returned by `other`.
+    pub fn except(&mut self, other: Query) -> &mut Self {
+        self.set_operation("EXCEPT", other)
+    }
+
+    /// Appends `INTERSECT other`, keeping the rows also returned by `other`.
+    pub fn intersect(&mut self, other: Query) -> &mut Self {
+        self.set_operation("INTERSECT", other)
+    }
+
+    fn set_operation(&mut self, op: &str, other: Query) -> &mut Self {
+        self.push(op);
+        self.append_buffer(" ");
+        self.append_query(other);
+        self
+    }
+
+    fn is_empty(&self) -> bool {
+        self.buffer.is_empty() && self.arg_indexes.is_empty()
+    }
+
+    /// Runs `f` after `sep`, or after a space if the previous fragment was not
+    /// a condition. If `f` appends nothing, the separator is removed again.
+    fn separate(&mut self, sep: &str, f: impl FnOnce(&mut Self)) {
+        let separated = self.separated;
+        let len = self.buffer.len();
+
+        if separated {
+            self.append_buffer(sep);
+        } else if !self.buffer.is_empty() && !self.buffer.ends_with('(') {
+            self.append_buffer(" ");
+        }
+
+        let start = self.buffer.len();
+        let args = self.arg_indexes.len();
+
+        // Groups are parenthesized even as the first condition, as a later
+        // separator could otherwise change their precedence.
+        self.separated = true;
+        f(self);
+
+        if self.buffer.len() == start && self.arg_indexes.len() == args {
+            self.cursor -= start - len;
+            self.buffer.truncate(len);
+            self.separated = separated;
+        } else {
+            self.separated = true;
+        }
+    }
+
+    fn append_query(&mut self, mut query: Query) {
+        self.args.append(&mut query.args);
+        self.arg_types.append(&mut query.arg_types);
+
+        self.arg_indexes
+            .extend(query.arg_indexes.into_iter().map(|i| i + self.cursor));
+
+        self.buffer.push_str(&query.buffer);
+        self.cursor += query.cursor;
+    }
+
+    fn insert_after_select(&mut self, query: &str) {
+        let pos = "SELECT".len();
+
+        assert!(
+            self.buffer
+                .get(..pos)
+                .is_some_and(|s| s.eq_ignore_ascii_case("SELECT")),
+            "query must start with SELECT"
+        );
+
+        self.insert_buffer(pos, query);
+    }
+
+    fn insert_buffer(&mut self, pos: usize, query: &str) {
+        self.buffer.insert_str(pos, query);
+        self.cursor += query.len();
+
+        for i in self.arg_indexes.iter_mut().filter(|i| **i >= pos) {
+            *i += query.len();
+        }
+    }
+
+    fn append_buffer(&mut self, query: &str) {
+        self.append_buffer_with_args(query, vec![]);
+    }
+
+    fn append_buffer_with_args(&mut self, query: &str, mut args: Vec<Box<dyn ToSql + Sync>>) {
+        for c in query.chars() {
+            if c == '?' {
+                self.arg_indexes.push(self.cursor);
+            } else {
+                self.buffer.push(c);
+                self.cursor += c.len_utf8();
+            }
+        }
+
+        self.arg_types.extend(args.iter().map(|_| None));
+        self.args.append(&mut args);
+    }
+}
+
+impl Display for Query {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut last = 0;
+
+        for (n, &i) in self.arg_indexes.iter().enumerate() {
+            f.write_str(&self.buffer[last..i])?;
+            f.write_char('$')?;
+            f.write_str(&(n + 1).to_string())?;
+            last = i;
+        }
+
+        f.write_str(&self.buffer[last..])
+    }
+}
+
+impl From<String> for Query {
+    /// Same as `Query::from_raw` without arguments.
+    fn from(sql: String) -> Self {
+        Query::from_raw(sql, vec![])
+    }
+}
+
+pub struct Paginated {
+    pub(crate) query: Query,
+    pub(crate) page: i64,
+    pub(crate) per_page: i64,
+}
+
+impl Paginated {
+    pub(crate) fn offset(&self) -> i64 {
+        (self.page.max(1) - 1) * self.per_page
+    }
+
+    /// Returns the SQL counting all rows and the SQL fetching the page, whose
+    /// `LIMIT` and `OFFSET` placeholders follow the query's arguments.
+    pub(crate) fn sql(&self) -> (String, String) {
+        let sql = self.query.to_string();
+        let args = self.query.args.len();
+
+        (
+            format!("SELECT count(*) FROM ({}) AS paginated", sql),
+            format!("{} LIMIT ${} OFFSET ${}", sql, args + 1, args + 2),
+        )
+    }
+}
+
+pub struct Page {
+    pub rows: Vec<Row>,
+    pub total: i64,
+    pub page: i64,
+    pub per_page: i64,
+}
+
+/// Builder for a `MERGE` statement, which requires PostgreSQL 15 or later.
+/// Convert it into a `Query` to run it, e.g. `Merge::into("stock t")
+/// .using(deliveries, "d").on("t.item = d.item").when_matched_update(...)`.
+///
+/// # Panics
+///
+/// Converting into a `Query` panics unless `using` and `on` were called.
+pub struct Merge {
+    target: String,
+    source: Option<Query>,
+    condition: Option<Query>,
+    clauses: Vec<Query>,
+}
+
+impl Merge {
+    /// Starts a `MERGE INTO target`, where `target` may include an alias.
+    pub fn into(target: &str) -> Self {
+        Self {
+            target: target.to_owned(),
+            source: None,
+            condition: None,
+            clauses: vec![],
+        }
+    }
+
+    /// Merges the rows of `source`, referred to as `alias`.
+    pub fn using(mut self, source: Query, alias: &str) -> Self {
+        let mut query = Query::new("(");
+        query.append_query(source);
+        query.append_buffer(") AS ");
+        query.append_buffer(alias);
+
+        self.source = Some(query);
+        self
+    }
+
+    /// Sets the join condition deciding whether a source row matches.
+    pub fn on<F>(mut self, condition: F) -> Self
+    where
+        F: Fragment,
+    {
+        self.condition = Some(Query::new(condition));
+        self
+    }
+
+    /// Adds `WHEN MATCHED THEN UPDATE SET assignments`.
+    pub fn when_matched_update<F>(mut self, assignments: F) -> Self
+    where
+        F: Fragment,
+    {
+        let mut clause = Query::new("WHEN MATCHED THEN UPDATE SET");
+        clause.push(assignments);
+
+        self.clauses.push(clause);
+        self
+    }
+
+    /// Adds `WHEN MATCHED THEN DELETE`.
+    pub fn when_matched_delete(mut self) -> Self {
+        self.clauses.push(Query::new("WHEN MATCHED THEN DELETE"));
+        self
+    }
+
+    /// Adds `WHEN NOT MATCHED THEN INSERT (cols) VALUES (values)`.
+    pub fn when_not_matched_insert<F>(mut self, cols: &[&str], values: F) -> Self
+    where
+        F: Fragment,
+    {
+        let mut clause = Query::new(
+            format!(
+                "WHEN NOT MATCHED THEN INSERT ({}) VALUES (",
+                cols.join(", ")
+            )
+            .as_str(),
+        );
+        values.push_to_query(&mut clause);
+        clause.append_buffer(")");
+
+        self.clauses.push(clause);
+        self
+    }
+}
+
+impl From<Merge> for Query {
+    fn from(merge: Merge) -> Self {
+        let source = merge
+            .source
+            .expect("MERGE requires a source, see Merge::using");
+        let condition = merge
+            .condition
+            .expect("MERGE requires a join condition, see Merge::on");
+
+        let mut query = Query::new(format!("MERGE INTO {} USING", merge.target).as_str());
+        query.push(source).push("ON").push(condition);
+
+        for clause in merge.clauses {
+            query.push(clause);
+        }
+
+        query
+    }
+}
+
+/// Builder for a `DELETE` statement, converted into a `Query` to run it, e.g.
+/// `Delete::from("sessions").where_(("id = ANY(?)", ids)).returning("id")`.
+/// Without any condition, every row of the table is deleted.
+pub struct Delete {
+    table: String,
+    conditions: Query,
+    returning: Option<Query>,
+}
+
+impl Delete {
+    pub fn from(table: &str) -> Self {
+        Self {
+            table: table.to_owned(),
+            conditions: Query::empty(),
+            returning: None,
+        }
+    }
+
+    /// Adds a condition, combined with earlier ones using `AND`.
+    pub fn where_<F>(mut self, condition: F) -> Self
+    where
+        F: Fragment,
+    {
+        self.conditions.and(condition);
+        self
+    }
+
+    /// Sets the `RETURNING` expressions, e.g. `"id"`, to get values of the
+    /// deleted rows back.
+    pub fn returning<F>(mut self, exprs: F) -> Self
+    where
+        F: Fragment,
+    {
+        self.returning = Some(Query::new(exprs));
+        self
+    }
+}
+
+impl From<Delete> for Query {
+    fn from(delete: Delete) -> Self {
+        let mut query = Query::new(format!("DELETE FROM {}", delete.table).as_str());
+
+        if !delete.conditions.is_empty() {
+            query.push("WHERE").push(delete.conditions);
+        }
+
+        if let Some(returning) = delete.returning {
+            query.push("RETURNING").push(returning);
+        }
+
+        query
+    }
+}
+
+/// Row returned by `Query::upsert_returning`.
+pub struct Upserted {
+    pub row: Row,
+
+    /// Whether the row was newly inserted rather than updated on conflict.
+    pub inserted: bool,
+}
+
+/// Sort direction for `Query::order_by_checked`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Order {
+    Asc,
+    Desc,
+}
+
+/// Pattern for `Query::like` and `Query::ilike`.
+///
+/// All variants except `Raw` escape the given term, so `%` and `_` in user
+/// input are matched literally.
+pub enum LikePattern<'a> {
+    /// Used as-is, wildcards included.
+    Raw(&'a str),
+    Exact(&'a str),
+    Prefix(&'a str),
+    Suffix(&'a str),
+    Contains(&'a str),
+}
+
+impl LikePattern<'_> {
+    fn render(&self) -> String {
+        match self {
+            LikePattern::Raw(p) => p.to_string(),
+            LikePattern::Exact(p) => escape_like(p),
+            LikePattern::Prefix(p) => format!("{}%", escape_like(p)),
+            LikePattern::Suffix(p) => format!("%{}", escape_like(p)),
+            LikePattern::Contains(p) => format!("%{}%", escape_like(p)),
+        }
+    }
+}
+
+impl<'a> From<&'a str> for LikePattern<'a> {
+    fn from(pattern: &'a str) -> Self {
+        LikePattern::Raw(pattern)
+    }
+}
+
+/// The `OVER (...)` clause of a window function. Created with `Window::over`,
+/// it includes the function and can be added to a column list with `comma`,
+/// e.g. `query.comma(Window::over("row_number()").order_by("score DESC"))`.
+/// Created with `Window::new`, it is pushed after the function instead.
+/// Functions and columns are inserted verbatim and must be trusted.
+#[derive(Default)]
+pub struct Window {
+    func: Option<String>,
+    partition_by: Vec<String>,
+    order_by: Vec<String>,
+    alias: Option<String>,
+}
+
+impl Window {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates `func OVER (...)`.
+    pub fn over(func: &str) -> Self {
+        Self {
+            func: Some(func.to_owned()),
+            ..Self::default()
+        }
+    }
+
+    pub fn partition_by(mut self, col: &str) -> Self {
+        self.partition_by.push(col.to_owned());
+        self
+    }
+
+    /// Adds a sort key, optionally followed by `ASC` or `DESC`.
+    pub fn order_by(mut self, col: &str) -> Self {
+        self.order_by.push(col.to_owned());
+        self
+    }
+
+    /// Appends `AS alias`, naming the resulting column.
+    pub fn alias(mut self, alias: &str) -> Self {
+        self.alias = Some(alias.to_owned());
+        self
+    }
+}
+
+/// Double-quotes an identifier, returning `None` unless every dot-separated
+/// part consists of ASCII letters, digits and underscores and does not start
+/// with a digit. Note that quoted identifiers are case-sensitive.
+pub fn quote_ident(ident: &str) -> Option<String> {
+    let parts = ident
+        .split('.')
+        .map(|part| {
+            let valid = part.chars().next().is_some_and(|c| !c.is_ascii_digit())
+                && part.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+
+            valid.then(|| format!("\"{}\"", part))
+        })
+        .collect::<Option<Vec<_>>>()?;
+
+    match parts.len() {
+        1 | 2 => Some(parts.join(".")),
+        _ => None,
+    }
+}
+
+const PRETTY_KEYWORDS: &[&str] = &[
+    "SELECT",
+    "FROM",
+    "WHERE",
+    "GROUP BY",
+    "HAVING",
+    "ORDER BY",
+    "LIMIT",
+    "OFFSET",
+    "UNION",
+    "LEFT JOIN",
+    "RIGHT JOIN",
+    "INNER JOIN",
+    "FULL JOIN",
+    "CROSS JOIN",
+    "JOIN",
+    "AND",
+    "OR",
+];
+
+fn pretty(sql: &str) -> String {
+    let mut out = String::with_capacity(sql.len() * 2);
+    // One entry per open parenthesis, `true` if it opens a subquery.
+    let mut parens: Vec<bool> = vec![];
+    let mut quote = None;
+    let mut rest = sql;
+
+    while let Some(c) = rest.chars().next() {
+        let depth = parens.iter().filter(|&&sub| sub).count();
+
+        if let Some(q) = quote {
+            if c == q {
+                quote = None;
+            }
+        } else if c == '\'' || c == '"' {
+            quote = Some(c);
+        } else if c == '(' {
+            let inner = rest[1..].trim_start();
+            parens.push(starts_with_keyword(inner, "SELECT") || starts_with_keyword(inner, "WITH"));
+        } else if c == ')' && parens.pop() == Some(true) {
+            out.truncate(out.trim_end().len());
+            out.push('\n');
+            out.push_str(&"  ".repeat(depth - 1));
+        } else if c.is_ascii_alphabetic()
+            && parens.last() != Some(&false)
+            && !out.ends_with(|p: char| p.is_alphanumeric() || p == '_')
+        {
+            if let Some(kw) = PRETTY_KEYWORDS
+                .iter()
+                .find(|kw| starts_with_keyword(rest, kw))
+            {
+                out.truncate(out.trim_end().len());
+
+                if !out.is_empty() {
+                    out.push('\n');
+                }
+
+                out.push_str(&"  ".repeat(depth));
+
+                if matches!(*kw, "AND" | "OR") {
+                    out.push_str("  ");
+                }
+
+                out.push_str(&rest[..kw.len()]);
+                rest = &rest[kw.len()..];
+                continue;
+            }
+        }
+
+        out.push(c);
+        rest = &rest[c.len_utf8()..];
+    }
+
+    out
+}
+
+fn starts_with_keyword(s: &str, kw: &str) -> bool {
+    s.get(..kw.len())
+        .is_some_and(|w| w.eq_ignore_ascii_case(kw))
+        && !s[kw.len()..].starts_with(|c: char| c.is_alphanumeric() || c == '_')
+}
+
+/// Escapes `\`, `%` and `_` so the string is matched literally by `LIKE`.
+pub fn escape_like(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+
+    for c in s.chars() {
+        if matches!(c, '\\' | '%' | '_') {
+            escaped.push('\\');
+        }
+
+        escaped.push(c);
+    }
+
+    escaped
+}
+
+pub trait Fragment {
+    fn push_to_query(self, query: &mut Query);
+}
+
+/// Turns a statement builder such as `Delete` or `Merge` into the `Query` it
+/// renders to, so it runs, numbers its placeholders and exposes its SQL and
+/// arguments (see `Query::into_sql_and_args`) the same way as any other query.
+pub trait IntoQuery {
+    fn into_query(self) -> Query;
+}
+
+impl IntoQuery for Query {
+    fn into_query(self) -> Query {
+        self
+    }
+}
+
+impl IntoQuery for Merge {
+    fn into_query(self) -> Query {
+        self.into()
+    }
+}
+
+impl IntoQuery for Delete {
+    fn into_query(self) -> Query {
+        self.into()
+    }
+}
+
+impl Fragment for &str {
+    fn push_to_query(self, query: &mut Query) {
+        query.append_buffer(self);
+    }
+}
+
+// Arguments are boxed and owned by the query, so they have to be `'static`.
+// Borrowed values can be bound through `Cloned`, which stores an owned copy.
+impl<T: ToSql + Sync + 'static> Fragment for (&str, T) {
+    fn push_to_query(self, query: &mut Query) {
+        query.append_buffer_with_args(self.0, vec![Box::new(self.1)]);
+    }
+}
+
+impl<T: ToSql + Sync + 'static> Fragment for (&str, T, T) {
+    fn push_to_query(self, query: &mut Query) {
+        query.append_buffer_with_args(self.0, vec![Box::new(self.1), Box::new(self.2)]);
+    }
+}
+
+impl<T: ToSql + Sync + 'static> Fragment for (&str, T, T, T) {
+    fn push_to_query(self, query: &mut Query) {
+        query.append_buffer_with_args(
+            self.0,
+            vec![Box::new(self.1), Box::new(self.2), Box::new(self.3)],
+        );
+    }
+}
+
+/// SQL with `?` placeholders together with its already boxed arguments, for
+/// fragments with a number of arguments only known at runtime, e.g. returned
+/// from a function building a filter.
+pub struct RawFragment {
+    pub sql: String,
+    pub args: Vec<Box<dyn ToSql + Sync>>,
+}
+
+impl Fragment for RawFragment {
+    fn push_to_query(self, query: &mut Query) {
+        query.append_buffer_with_args(&self.sql, self.args);
+    }
+}
+
+/// Binds an owned copy of borrowed data, e.g. `("name = ?", Cloned(&name))`
+/// with `name: String` still needed after building the query.
+pub struct Cloned<'a, T: ?Sized>(pub &'a T);
+
+impl<T> Fragment for (&str, Cloned<'_, T>)
+where
+    T: ?Sized + ToOwned,
+    T::Owned: ToSql + Sync + 'static,
+{
+    fn push_to_query(self, query: &mut Query) {
+        (self.0, self.1 .0.to_owned()).push_to_query(query);
+    }
+}
+
+/// An argument bound with an explicit type, for parameters whose type the
+/// server cannot infer, e.g. `("? IS NULL", Typed(None::<i32>, Type::INT4))`.
+pub struct Typed<T>(pub T, pub Type);
+
+impl<T: ToSql + Sync + 'static> Fragment for (&str, Typed<T>) {
+    fn push_to_query(self, query: &mut Query) {
+        let Typed(value, ty) = self.1;
+        query.append_buffer_with_args(self.0, vec![Box::new(value)]);
+        *query.arg_types.last_mut().unwrap() = Some(ty);
+    }
+}
+
+impl<F> Fragment for F
+where
+    F: FnOnce(&mut Query),
+{
+    fn push_to_query(self, query: &mut Query) {
+        let separated = query.separated;
+
+        if separated {
+            query.separated = false;
+            query.append_buffer("(");
+        }
+
+        let start = query.buffer.len();
+        let args = query.arg_indexes.len();
+
+        (self)(query);
+
+        if separated {
+            query.separated = true;
+
+            // An empty group would be invalid SQL, so drop its parenthesis.
+            if query.buffer.len() == start && query.arg_indexes.len() == args {
+                query.buffer.pop();
+                query.cursor -= 1;
+            } else {
+                query.append_buffer(")");
+            }
+        }
+    }
+}
+
+impl Fragment for Window {
+    fn push_to_query(self, query: &mut Query) {
+        let mut clauses = vec![];
+
+        if !self.partition_by.is_empty() {
+            clauses.push(format!("PARTITION BY {}", self.partition_by.join(", ")));
+        }
+
+        if !self.order_by.is_empty() {
+            clauses.push(format!("ORDER BY {}", self.order_by.join(", ")));
+        }
+
+        let mut sql = format!("OVER ({})", clauses.join(" "));
+
+        if let Some(func) = self.func {
+            sql = format!("{} {}", func, sql);
+        }
+
+        if let Some(alias) = self.alias {
+            sql = format!("{} AS {}", sql, alias);
+        }
+
+        query.append_buffer(&sql);
+    }
+}
+
+impl Fragment for Query {
+    fn push_to_query(self, query: &mut Query) {
+        // Like for closures, an empty group would be invalid SQL.
+        if self.is_empty() {
+            return;
+        }
+
+        if query.separated {
+            query.append_buffer("(");
+        }
+
+        query.append_query(self);
+
+        if query.separated {
+            query.append_buffer(")");
+        }
+    }
+}
+
+#[test]
+fn simple_query() {
+    let mut query = Query::new("SELECT");
+    query.comma("a");
+    query.comma("b");
+    query.comma("c");
+    query.push("FROM foobar WHERE");
+    query.and("foo = 'bar'");
+    query.and(("bar = ?", 1));
+
+    query.and(|q: &mut Query| {
+        q.or(("d = ?", 10));
+        q.or(("e != ?", 20));
+    });
+
+    assert_eq!(
+        query.to_string(),
+        "SELECT a,b,c FROM foobar WHERE foo = 'bar' AND bar = $1 AND (d = $2 OR e != $3)"
+    );
+}
+
+#[test]
+fn query_concatenation() {
+    let mut query_a = Query::empty();
+
+    query_a.push(|query: &mut Query| {
+        query.or(("c = ?", 3));
+        query.or("d = 4");
+        query.or(("e = ?", 4));
+    });
+
+    let mut query_b = Query::new("SELECT * FROM test WHERE");
+    query_b.and(("a = ?", 1));
+    query_b.and(("b = ?", 2));
+    query_b.and(query_a);
+
+    assert_eq!(
+        query_b.to_string(),
+        "SELECT * FROM test WHERE a = $1 AND b = $2 AND (c = $3 OR d = 4 OR e = $4)"
+    );
+
+    let mut fields = Query::empty();
+    fields.comma("foo");
+    fields.comma("bar");
+    fields.comma(("? as foobar", 1000));
+
+    let mut query = Query::new("SELECT");
+    query.push(fields);
+    query.push("FROM my_table");
+
+    assert_eq!(
+        query.to_string(),
+        "SELECT foo,bar,$1 as foobar FROM my_table"
+    );
+}
+
+#[test]
+fn distinct() {
+    let mut query = Query::new("SELECT");
+    query.comma("a");
+    query.comma(("? as b", 1));
+    query.push("FROM foobar");
+    query.distinct();
+
+    assert_eq!(query.to_string(), "SELECT DISTINCT a,$1 as b FROM foobar");
+
+    let mut query = Query::new("select");
+    query.comma("a");
+    query.comma("b");
+    query.push("FROM foobar ORDER BY a, b");
+    query.distinct_on("a");
+
+    assert_eq!(
+        query.to_string(),
+        "select DISTINCT ON (a) a,b FROM foobar ORDER BY a, b"
+    );
+}
+
+#[test]
+#[should_panic(expected = "query must start with SELECT")]
+fn distinct_without_select() {
+    Query::new("DELETE FROM foobar").distinct();
+}
+
+#[test]
+fn like() {
+    assert_eq!(escape_like(r"50%_off\"), r"50\%\_off\\");
+    assert_eq!(LikePattern::Prefix("a_b").render(), r"a\_b%");
+    assert_eq!(LikePattern::Contains("50%").render(), r"%50\%%");
+    assert_eq!(LikePattern::from("a_%").render(), "a_%");
+
+    let mut query = Query::new("SELECT * FROM foobar WHERE");
+    query.like("a", "foo%");
+    query.ilike("b", LikePattern::Contains("50%"));
+
+    assert_eq!(
+        query.to_string(),
+        "SELECT * FROM foobar WHERE a LIKE $1 AND b ILIKE $2"
+    );
+}
+
+#[test]
+fn paginate() {
+    let paginated = Query::new("SELECT * FROM foobar").paginate(3, 20);
+    assert_eq!(paginated.offset(), 40);
+
+    let paginated = Query::new("SELECT * FROM foobar").paginate(0, 20);
+    assert_eq!(paginated.offset(), 0);
+    assert_eq!(
+        paginated.sql(),
+        (
+            "SELECT count(*) FROM (SELECT * FROM foobar) AS paginated".to_owned(),
+            "SELECT * FROM foobar LIMIT $1 OFFSET $2".to_owned()
+        )
+    );
+
+    let mut query = Query::new("SELECT * FROM foobar WHERE");
+    query.and(("a = ?", 1)).and(("b = ?", 2));
+    assert_eq!(
+        query.paginate(2, 10).sql(),
+        (
+            "SELECT count(*) FROM (SELECT * FROM foobar WHERE a = $1 AND b = $2) AS paginated"
+                .to_owned(),
+            "SELECT * FROM foobar WHERE a = $1 AND b = $2 LIMIT $3 OFFSET $4".to_owned()
+        )
+    );
+}
+
+#[test]
+fn into_sql_and_args() {
+    let mut query = Query::new("SELECT * FROM foobar WHERE");
+    query.and(("a = ?", 1));
+    query.and(("b = ?", "foo"));
+
+    let (sql, args) = query.into_sql_and_args();
+
+    assert_eq!(sql, "SELECT * FROM foobar WHERE a = $1 AND b = $2");
+    assert_eq!(args.len(), 2);
+}
+
+#[test]
+fn typed_args() {
+    let mut query = Query::new("SELECT * FROM foobar WHERE");
+    query.and(("a = ?", 1));
+    query.and(("b = ?", Typed(None::<i64>, Type::INT8)));
+    query.push(("AND c = ?", 2));
+
+    assert_eq!(query.param_types(), vec![Type::UNKNOWN, Type::INT8]);
+    assert_eq!(Query::new(("a = ?", 1)).param_types(), vec![]);
+}
+
+#[test]
+fn cloned_args() {
+    let name = String::from("foo");
+    let tags = vec![1, 2];
+
+    let mut query = Query::new("SELECT * FROM foobar WHERE");
+    query.and(("name = ?", Cloned(name.as_str())));
+    query.and(("tags = ?", Cloned(&tags)));
+
+    assert_eq!(
+        query.to_string(),
+        "SELECT * FROM foobar WHERE name = $1 AND tags = $2"
+    );
+    assert_eq!(query.into_args().len(), 2);
+    assert_eq!(name, "foo");
+}
+
+#[test]
+fn subquery() {
+    let mut ids = Query::new("SELECT id FROM bar WHERE");
+    ids.and(("a = ?", 1));
+    ids.and(("b = ?", 2));
+
+    let mut query = Query::new("SELECT * FROM foo");
+    query.push_subquery("WHERE id IN", ids);
+    query.and(("c = ?", 3));
+
+    assert_eq!(
+        query.to_string(),
+        "SELECT * FROM foo WHERE id IN (SELECT id FROM bar WHERE a = $1 AND b = $2) AND c = $3"
+    );
+    assert_eq!(query.into_args().len(), 3);
+}
+
+#[test]
+fn reset() {
+    let mut query = Query::new("SELECT * FROM foobar WHERE");
+    query.and(("a = ?", 1));
+    query.reset();
+    query.and(("b = ?", 2));
+
+    assert_eq!(query.to_string(), "b = $1");
+    assert_eq!(query.into_args().len(), 1);
+}
+
+#[test]
+fn from_raw() {
+    let query = Query::from_raw(
+        "SELECT * FROM foobar WHERE a = $1 AND b ? $2",
+        vec![Box::new(1), Box::new("foo")],
+    );
+
+    assert_eq!(
+        query.to_string(),
+        "SELECT * FROM foobar WHERE a = $1 AND b ? $2"
+    );
+    assert_eq!(query.into_args().len(), 2);
+
+    let query = Query::from(String::from("SELECT 1 ?"));
+    assert_eq!(query.to_string(), "SELECT 1 ?");
+}
+
+#[test]
+fn identifiers() {
+    assert_eq!(quote_ident("name").as_deref(), Some("\"name\""));
+    assert_eq!(
+        quote_ident("public.user_1").as_deref(),
+        Some("\"public\".\"user_1\"")
+    );
+    assert_eq!(quote_ident(""), None);
+    assert_eq!(quote_ident("1a"), None);
+    assert_eq!(quote_ident("a.b.c"), None);
+    assert_eq!(quote_ident("a\"; DROP TABLE b; --"), None);
+
+    let mut query = Query::new("SELECT * FROM foobar ORDER BY");
+    query.push_ident("created_at");
+    query.push("DESC");
+
+    assert_eq!(
+        query.to_string(),
+        "SELECT * FROM foobar ORDER BY \"created_at\" DESC"
+    );
+}
+
+#[test]
+#[should_panic(expected = "unsafe identifier")]
+fn unsafe_identifier() {
+    Query::new("SELECT").push_ident("a b");
+}
+
+#[test]
+fn push_raw() {
+    let mut query = Query::new(("SELECT * FROM foobar WHERE a = ?", 1));
+    query.push_raw("AND data ? 'key' AND b = $1");
+    query.push(("AND c = ?", 2));
+
+    assert_eq!(
+        query.to_string(),
+        "SELECT * FROM foobar WHERE a = $1 AND data ? 'key' AND b = $1 AND c = $2"
+    );
+    assert_eq!(query.into_args().len(), 2);
+}
+
+#[test]
+fn pretty_string() {
+    let mut sub = Query::new("SELECT id FROM bar");
+    sub.push("WHERE").and(("x = ?", 2)).and("(y OR z)");
+
+    let mut query = Query::new("SELECT count(*), 'a where b' FROM foo JOIN baz ON baz.id = foo.id");
+    query.push("WHERE").and(("a = ?", 1));
+    query.and("ordered").push_subquery("AND foo.bar_id IN", sub);
+    query.push("ORDER BY a LIMIT 10");
+
+    assert_eq!(
+        query.to_pretty_string(),
+        "SELECT count(*), 'a where b'
+FROM foo
+JOIN baz ON baz.id = foo.id
+WHERE a = $1
+  AND ordered
+  AND foo.bar_id IN (
+  SELECT id
+  FROM bar
+  WHERE x = $2
+    AND (y OR z)
+)
+ORDER BY a
+LIMIT 10"
+    );
+}
+
+#[test]
+fn not() {
+    let mut query = Query::new("SELECT * FROM foobar WHERE");
+    query.and(("a = ?", 1)).not(|q: &mut Query| {
+        q.or(("b = ?", 2)).or(("c = ?", 3));
+    });
+
+    assert_eq!(
+        query.to_string(),
+        "SELECT * FROM foobar WHERE a = $1 AND NOT (b = $2 OR c = $3)"
+    );
+
+    let mut query = Query::new("SELECT * FROM foobar WHERE");
+    query.not("deleted").and(("a = ?", 1));
+
+    assert_eq!(
+        query.to_string(),
+        "SELECT * FROM foobar WHERE NOT (deleted) AND a = $1"
+    );
+
+    let mut query = Query::new("SELECT * FROM foobar WHERE");
+    query.or(("a = ?", 1)).or_not(("b = ?", 2));
+
+    assert_eq!(
+        query.to_string(),
+        "SELECT * FROM foobar WHERE a = $1 OR NOT (b = $2)"
+    );
+}
+
+#[test]
+fn empty_group() {
+    let (b, c): (Option<i32>, Option<i32>) = (None, None);
+
+    let mut query = Query::new("SELECT * FROM foobar WHERE");
+    query.and(("a = ?", 1));
+    query.and(|q: &mut Query| {
+        if let Some(b) = b {
+            q.or(("b = ?", b));
+        }
+        if let Some(c) = c {
+            q.or(("c = ?", c));
+        }
+    });
+    query.and(("d = ?", 4));
+
+    assert_eq!(
+        query.to_string(),
+        "SELECT * FROM foobar WHERE a = $1 AND d = $2"
+    );
+
+    let mut query = Query::new("SELECT * FROM foobar WHERE");
+    query
+        .and(("a = ?", 1))
+        .and(Query::empty())
+        .or(Query::empty())
+        .and(("d = ?", 4));
+
+    assert_eq!(
+        query.to_string(),
+        "SELECT * FROM foobar WHERE a = $1 AND d = $2"
+    );
+}
+
+#[test]
+fn leading_separators() {
+    let mut query = Query::empty();
+    query.and(("? = a", 1)).and("b");
+    assert_eq!(query.to_string(), "$1 = a AND b");
+
+    let mut query = Query::empty();
+    query.or("a").or("b");
+    assert_eq!(query.to_string(), "a OR b");
+
+    let mut query = Query::empty();
+    query.comma("a").comma("b");
+    assert_eq!(query.to_string(), "a,b");
+
+    let mut query = Query::new("SELECT * FROM foobar WHERE");
+    query.and("a").or(|q: &mut Query| {
+        q.and("b").and("c");
+    });
+    query.and(|q: &mut Query| {
+        q.or(|q: &mut Query| {
+            q.and("d").and("e");
+        });
+        q.or("f");
+    });
+    assert_eq!(
+        query.to_string(),
+        "SELECT * FROM foobar WHERE a OR (b AND c) AND ((d AND e) OR f)"
+    );
+}
+
+#[test]
+fn literals() {
+    let mut query = Query::new(("SELECT * FROM foobar WHERE a = ?", 1));
+    query.push("FETCH FIRST").push_lit(10).push("ROWS ONLY");
+
+    assert_eq!(
+        query.to_string(),
+        "SELECT * FROM foobar WHERE a = $1 FETCH FIRST 10 ROWS ONLY"
+    );
+}
+
+#[test]
+fn with_recursive() {
+    let anchor = Query::new(("SELECT id, parent_id FROM nodes WHERE id = ?", 7));
+    let mut recursive = Query::new("SELECT n.id, n.parent_id FROM nodes n");
+    recursive
+        .push("JOIN ancestors a ON n.id = a.parent_id WHERE")
+        .and(("n.depth < ?", 10));
+
+    let mut query = Query::empty();
+    query.with_recursive("ancestors", &["id", "parent_id"], anchor, recursive);
+    query
+        .push("SELECT id FROM ancestors WHERE")
+        .and(("id != ?", 7));
+
+    assert_eq!(
+        query.to_string(),
+        "WITH RECURSIVE ancestors(id, parent_id) AS (\
+         SELECT id, parent_id FROM nodes WHERE id = $1 UNION ALL \
+         SELECT n.id, n.parent_id FROM nodes n \
+         JOIN ancestors a ON n.id = a.parent_id WHERE n.depth < $2) \
+         SELECT id FROM ancestors WHERE id != $3"
+    );
+    assert_eq!(query.into_args().len(), 3);
+}
+
+#[test]
+fn between() {
+    let mut query = Query::new("SELECT * FROM foobar WHERE");
+    query.and(("a = ?", 1)).between("b", 2, 3);
+    query.or(|q: &mut Query| {
+        q.not_between("c", 4, 5);
+    });
+
+    assert_eq!(
+        query.to_string(),
+        "SELECT * FROM foobar WHERE a = $1 AND b BETWEEN $2 AND $3 OR (c NOT BETWEEN $4 AND $5)"
+    );
+    assert_eq!(query.into_args().len(), 5);
+}
+
+#[test]
+fn null_checks() {
+    let mut query = Query::new("SELECT * FROM foobar WHERE");
+    query
+        .is_null("deleted_at")
+        .and(("a = ?", 1))
+        .is_not_null("b");
+
+    assert_eq!(
+        query.to_string(),
+        "SELECT * FROM foobar WHERE deleted_at IS NULL AND a = $1 AND b IS NOT NULL"
+    );
+}
+
+#[test]
+fn set_operations() {
+    let mut query = Query::new(("SELECT id FROM a WHERE x = ?", 1));
+    query.except(Query::new(("SELECT id FROM b WHERE y = ?", 2)));
+    query.intersect(Query::new(("SELECT id FROM c WHERE z = ?", 3)));
+
+    assert_eq!(
+        query.to_string(),
+        "SELECT id FROM a WHERE x = $1 \
+         EXCEPT SELECT id FROM b WHERE y = $2 \
+         INTERSECT SELECT id FROM c WHERE z = $3"
+    );
+    assert_eq!(query.into_args().len(), 3);
+}
+
+#[test]
+fn search() {
+    let mut query = Query::new("SELECT * FROM users WHERE");
+    query.and(("active = ?", true));
+    query.search(&["name", "email"], "50%_off");
+
+    assert_eq!(
+        query.to_string(),
+        "SELECT * FROM users WHERE active = $1 AND (name ILIKE $2 OR email ILIKE $3)"
+    );
+
+    assert_eq!(query.into_args().len(), 3);
+}
+
+#[test]
+fn text_search() {
+    let mut query = Query::new("SELECT * FROM posts WHERE");
+    query.and(("published = ?", true));
+    query.text_search("search", "fat cats", "english");
+
+    assert_eq!(
+        query.to_string(),
+        "SELECT * FROM posts WHERE published = $1 \
+         AND search @@ plainto_tsquery($2::text::regconfig, $3)"
+    );
+    assert_eq!(query.into_args().len(), 3);
+}
+
+#[test]
+fn insert_from_select() {
+    let mut select = Query::new("SELECT id, name FROM users WHERE");
+    select
+        .and(("created_at < ?", 10))
+        .and(("active = ?", false));
+
+    let mut query = Query::insert_from_select("archived_users", &["id", "name"], select);
+    query.push("RETURNING id");
+
+    assert_eq!(
+        query.to_string(),
+        "INSERT INTO archived_users (id, name) \
+         SELECT id, name FROM users WHERE created_at < $1 AND active = $2 RETURNING id"
+    );
+    assert_eq!(query.into_args().len(), 2);
+}
+
+#[test]
+fn timestamps() {
+    let mut query = Query::new("UPDATE foobar SET updated_at =");
+    query.push_now();
+    assert_eq!(query.to_string(), "UPDATE foobar SET updated_at = now()");
+
+    #[cfg(feature = "with-chrono-0_4")]
+    {
+        let ts = chrono::DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+
+        let mut query = Query::new(("UPDATE foobar SET a = ?,", 1));
+        query.push("updated_at =").push_timestamp(ts);
+        assert_eq!(
+            query.to_string(),
+            "UPDATE foobar SET a = $1, updated_at = $2"
+        );
+        assert_eq!(format!("{:?}", query.into_args()[1]), format!("{:?}", ts));
+    }
+}
+
+#[test]
+fn join_fragments() {
+    let changes = [
+        ("name", Some("alice")),
+        ("email", None),
+        ("bio", Some("hi")),
+    ];
+
+    let mut query = Query::new("UPDATE users SET");
+    query
+        .join_fragments(", ", changes, |q, (col, value)| {
+            if let Some(value) = value {
+                q.push_ident(col).push(("= ?", value.to_string()));
+            }
+        })
+        .push(("WHERE id = ?", 1));
+
+    assert_eq!(
+        query.to_string(),
+        r#"UPDATE users SET "name" = $1, "bio" = $2 WHERE id = $3"#
+    );
+    assert_eq!(query.into_args().len(), 3);
+
+    let mut query = Query::new("SELECT");
+    query.join_fragments(", ", Vec::<&str>::new(), |q, col| {
+        q.push(col);
+    });
+
+    assert_eq!(query.to_string(), "SELECT");
+}
+
+#[test]
+fn window() {
+    let mut query = Query::select(&["name"]);
+    query
+        .comma(
+            Window::over("rank()")
+                .partition_by("department")
+                .order_by("salary DESC")
+                .order_by("name")
+                .alias("rank"),
+        )
+        .comma(Window::over("count(*)"))
+        .comma("salary")
+        .push("FROM employees WHERE")
+        .and(("active = ?", true));
+
+    assert_eq!(
+        query.to_string(),
+        "SELECT name,rank() OVER (PARTITION BY department ORDER BY salary DESC, name) AS rank,\
+         count(*) OVER (),salary FROM employees WHERE active = $1"
+    );
+
+    let mut query = Query::new("SELECT row_number()");
+    query.push(Window::new().order_by("id")).push("FROM t");
+    assert_eq!(
+        query.to_string(),
+        "SELECT row_number() OVER (ORDER BY id) FROM t"
+    );
+}
+
+#[test]
+fn merge() {
+    let mut deliveries = Query::new("SELECT item, qty FROM deliveries WHERE");
+    deliveries.and(("day = ?", 3));
+
+    let query: Query = Merge::into("stock t")
+        .using(deliveries, "d")
+        .on("t.item = d.item")
+        .when_matched_update(("qty = t.qty + d.qty, updated_by = ?", "merge"))
+        .when_not_matched_insert(
+            &["item", "qty", "updated_by"],
+            ("d.item, d.qty, ?", "merge"),
+        )
+        .into();
+
+    assert_eq!(
+        query.to_string(),
+        "MERGE INTO stock t USING (SELECT item, qty FROM deliveries WHERE day = $1) AS d \
+         ON t.item = d.item \
+         WHEN MATCHED THEN UPDATE SET qty = t.qty + d.qty, updated_by = $2 \
+         WHEN NOT MATCHED THEN INSERT (item, qty, updated_by) VALUES (d.item, d.qty, $3)"
+    );
+    assert_eq!(query.into_args().len(), 3);
+}
+
+#[test]
+fn select() {
+    let mut manual = Query::new("SELECT");
+    manual.comma("a").comma("b").comma("c");
+
+    assert_eq!(
+        Query::select(&["a", "b", "c"]).to_string(),
+        manual.to_string()
+    );
+
+    manual.push("FROM foobar");
+    let mut query = Query::select_from(&["a", "b", "c"], "foobar");
+
+    assert_eq!(query.to_string(), manual.to_string());
+
+    query.push("WHERE").and(("a = ?", 1));
+    assert_eq!(query.to_string(), "SELECT a,b,c FROM foobar WHERE a = $1");
+}
+
+#[test]
+fn row_locks() {
+    let jobs = || {
+        let mut query = Query::new("SELECT * FROM jobs WHERE");
+        query.and(("state = ?", "queued")).push("LIMIT 1");
+        query
+    };
+
+    assert_eq!(
+        jobs().for_update().to_string(),
+        "SELECT * FROM jobs WHERE state = $1 LIMIT 1 FOR UPDATE"
+    );
+    assert_eq!(
+        jobs().for_update_skip_locked().to_string(),
+        "SELECT * FROM jobs WHERE state = $1 LIMIT 1 FOR UPDATE SKIP LOCKED"
+    );
+    assert_eq!(
+        jobs().for_share().to_string(),
+        "SELECT * FROM jobs WHERE state = $1 LIMIT 1 FOR SHARE"
+    );
+}
+
+#[test]
+fn raw_fragment() {
+    fn any_of(col: &str, values: &[i32]) -> RawFragment {
+        let placeholders = vec!["?"; values.len()].join(", ");
+
+        RawFragment {
+            sql: format!("{} IN ({})", col, placeholders),
+            args: values
+                .iter()
+                .map(|v| Box::new(*v) as Box<dyn ToSql + Sync>)
+                .collect(),
+        }
+    }
+
+    let mut query = Query::new("SELECT * FROM foobar WHERE");
+    query
+        .and(("a = ?", "x"))
+        .and(any_of("b", &[1, 2, 3]))
+        .and(("c = ?", true));
+
+    assert_eq!(
+        query.to_string(),
+        "SELECT * FROM foobar WHERE a = $1 AND b IN ($2, $3, $4) AND c = $5"
+    );
+    assert_eq!(query.into_args().len(), 5);
+}
+
+#[test]
+fn casts() {
+    let mut query = Query::new("SELECT * FROM events WHERE");
+    query
+        .push_cast("", None::<i64>, "bigint")
+        .push("IS NULL OR")
+        .push_cast("id >", Some(10i64), "bigint");
+
+    assert_eq!(
+        query.to_string(),
+        "SELECT * FROM events WHERE $1::bigint IS NULL OR id > $2::bigint"
+    );
+    assert_eq!(query.into_args().len(), 2);
+}
+
+#[test]
+fn composite_arrays() {
+    use tokio_postgres::types::{Field, Kind};
+
+    let items = vec![
+        Item {
+            id: 1,
+            kind: "a".into(),
+        },
+        Item {
+            id: 2,
+            kind: "b".into(),
+        },
+    ];
+
+    let mut query = Query::new("SELECT * FROM users WHERE");
+    query.and(("active = ?", true)).push_composite_array(
+        "AND id IN (SELECT id FROM import_items(?))",
+        items,
+        "item",
+    );
+
+    assert_eq!(
+        query.to_string(),
+        "SELECT * FROM users WHERE active = $1 AND id IN (SELECT id FROM import_items($2::item[]))"
+    );
+
+    let fields = vec![
+        Field::new("id".into(), Type::INT8),
+        Field::new("kind".into(), Type::TEXT),
+    ];
+    let item = Type::new(
+        "item".into(),
+        100_000,
+        Kind::Composite(fields),
+        "public".into(),
+    );
+    let items = Type::new("_item".into(), 100_001, Kind::Array(item), "public".into());
+
+    let args = query.into_args();
+    let mut buf = bytes::BytesMut::new();
+    assert!(args[1].to_sql_checked(&items, &mut buf).is_ok());
+}
+
+#[cfg(test)]
+#[derive(Debug)]
+struct Item {
+    id: i64,
+    kind: String,
+}
+
+#[cfg(test)]
+impl ToSql for Item {
+    fn to_sql(
+        &self,
+        _: &Type,
+        out: &mut bytes::BytesMut,
+    ) -> Result<tokio_postgres::types::IsNull, Box<dyn std::error::Error + Sync + Send>> {
+        use bytes::BufMut;
+
+        out.put_i32(2);
+        out.put_u32(Type::INT8.oid());
+        out.put_i32(8);
+        out.put_i64(self.id);
+        out.put_u32(Type::TEXT.oid());
+        out.put_i32(self.kind.len() as i32);
+        out.put_slice(self.kind.as_bytes());
+
+        Ok(tokio_postgres::types::IsNull::No)
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        ty.name() == "item" && matches!(ty.kind(), tokio_postgres::types::Kind::Composite(_))
+    }
+
+    tokio_postgres::types::to_sql_checked!();
+}
+
+#[test]
+fn returning_inserted() {
+    let query = Query::new((
+        "INSERT INTO foobar (id) VALUES (?) ON CONFLICT (id) DO UPDATE SET a = 1",
+        1,
+    ));
+
+    assert_eq!(
+        query.returning_inserted().to_string(),
+        "INSERT INTO foobar (id) VALUES ($1) ON CONFLICT (id) DO UPDATE SET a = 1 RETURNING *, (xmax = 0) AS inserted"
+    );
+}
+
+#[test]
+fn explain() {
+    let mut query = Query::new("SELECT * FROM foobar WHERE");
+    query.and(("a = ?", 1)).and(("b = ?", 2));
+
+    assert_eq!(
+        query.explain().to_string(),
+        "EXPLAIN (FORMAT JSON) SELECT * FROM foobar WHERE a = $1 AND b = $2"
+    );
+
+    let query = Query::new(("DELETE FROM foobar WHERE a = ?", 1)).explain_analyze();
+
+    assert_eq!(
+        query.to_string(),
+        "EXPLAIN (ANALYZE, FORMAT JSON) DELETE FROM foobar WHERE a = $1"
+    );
+    assert_eq!(query.into_args().len(), 1);
+}
+
+#[test]
+fn delete() {
+    let query: Query = Delete::from("sessions")
+        .where_(("id = ANY(?)", vec![1, 2, 3]))
+        .where_(|q: &mut Query| {
+            q.or(("expires_at < ?", 100)).or("revoked");
+        })
+        .returning(("id, ? AS batch", "cleanup"))
+        .into();
+
+    assert_eq!(
+        query.to_string(),
+        "DELETE FROM sessions WHERE id = ANY($1) AND (expires_at < $2 OR revoked) \
+         RETURNING id, $3 AS batch"
+    );
+    assert_eq!(query.into_args().len(), 3);
+
+    let query: Query = Delete::from("sessions").into();
+    assert_eq!(query.to_string(), "DELETE FROM sessions");
+}
+
+#[test]
+fn values_table() {
+    let mut query = Query::new("SELECT u.* FROM users u JOIN");
+    query
+        .values_table(
+            "input",
+            &[("id", Type::INT4), ("email", Type::TEXT)],
+            vec![
+                vec![Box::new(1), Box::new("a@example.com")],
+                vec![Box::new(2), Box::new("b@example.com")],
+            ],
+        )
+        .push("ON u.id = input.id WHERE")
+        .and(("u.active = ?", true));
+
+    assert_eq!(
+        query.to_string(),
+        "SELECT u.* FROM users u JOIN (VALUES ($1, $2), ($3, $4)) AS input (id, email) \
+         ON u.id = input.id WHERE u.active = $5"
+    );
+    assert_eq!(
+        query.param_types(),
+        vec![Type::INT4, Type::TEXT, Type::INT4, Type::TEXT]
+    );
+}
+
+#[test]
+fn conditional_conditions() {
+    let build = |active: bool, name: Option<&str>, min_age: Option<i32>| {
+        let mut query = Query::new("SELECT * FROM users WHERE");
+        query
+            .and_if(active, "active")
+            .and_some(name, |name| ("name = ?", name.to_string()))
+            .and_some(min_age, |age| ("age >= ?", age));
+        query
+    };
+
+    let query = build(true, Some("alice"), Some(18));
+    assert_eq!(
+        query.to_string(),
+        "SELECT * FROM users WHERE active AND name = $1 AND age >= $2"
+    );
+    assert_eq!(query.into_args().len(), 2);
+
+    let query = build(false, None, Some(18));
+    assert_eq!(query.to_string(), "SELECT * FROM users WHERE age >= $1");
+    assert_eq!(query.into_args().len(), 1);
+
+    let query = build(false, None, None);
+    assert_eq!(query.to_string(), "SELECT * FROM users WHERE");
+}
+
+#[cfg(feature = "with-uuid-1")]
+#[test]
+fn uuid_args() {
+    use crate::types::Uuid;
+
+    let mut query = Query::new("SELECT * FROM users WHERE");
+    query.and(("id = ?", Uuid::nil()));
+
+    assert_eq!(query.to_string(), "SELECT * FROM users WHERE id = $1");
+    assert_eq!(query.into_args().len(), 1);
+}
+
+#[test]
+fn order_by_checked() {
+    let allowed = ["name", "created_at"];
+
+    let mut query = Query::new("SELECT * FROM users");
+    query
+        .order_by_checked("created_at", &allowed, Order::Desc)
+        .unwrap();
+    assert_eq!(
+        query.to_string(),
+        "SELECT * FROM users ORDER BY created_at DESC"
+    );
+
+    let mut query = Query::new("SELECT * FROM users");
+    assert!(matches!(
+        query.order_by_checked("name; DROP TABLE users", &allowed, Order::Asc),
+        Err(QueryError::OrderNotAllowed(_))
+    ));
+    assert_eq!(query.to_string(), "SELECT * FROM users");
+}
+
+#[test]
+fn into_query() {
+    let (sql, args) = Delete::from("sessions")
+        .where_(("user_id = ?", 7))
+        .into_query()
+        .into_sql_and_args();
+
+    assert_eq!(sql, "DELETE FROM sessions WHERE user_id = $1");
+    assert_eq!(args.len(), 1);
+
+    let query = Query::new(("SELECT * FROM users WHERE id = ?", 1));
+    assert_eq!(
+        query.into_query().to_string(),
+        "SELECT * FROM users WHERE id = $1"
+    );
+}
+
+#[test]
+fn optional_limits() {
+    let query = |limit, offset| {
+        let mut query = Query::new("SELECT * FROM users WHERE");
+        query
+            .and(("active = ?", true))
+            .limit_opt(limit)
+            .offset_opt(offset);
+        query
+    };
+
+    let both = query(Some(10), Some(20));
+    assert_eq!(
+        both.to_string(),
+        "SELECT * FROM users WHERE active = $1 LIMIT $2 OFFSET $3"
+    );
+    assert_eq!(both.into_args().len(), 3);
+
+    let offset = query(None, Some(20));
+    assert_eq!(
+        offset.to_string(),
+        "SELECT * FROM users WHERE active = $1 OFFSET $2"
+    );
+    assert_eq!(offset.into_args().len(), 2);
+
+    let neither = query(None, None);
+    assert_eq!(neither.to_string(), "SELECT * FROM users WHERE active = $1");
+    assert_eq!(neither.into_args().len(), 1);
+}
+
+#[test]
+fn exists() {
+    let mut orders = Query::new("SELECT 1 FROM orders o WHERE");
+    orders.and("o.user_id = u.id").and(("o.total > ?", 100));
+
+    let mut refunds = Query::new("SELECT 1 FROM refunds r WHERE");
+    refunds
+        .and("r.user_id = u.id")
+        .and(("r.reason = ?", "fraud"));
+
+    let mut query = Query::new("SELECT * FROM users u WHERE");
+    query
+        .and(("u.active = ?", true))
+        .where_exists(orders)
+        .where_not_exists(refunds)
+        .and(("u.country = ?", "NL"));
+
+    assert_eq!(
+        query.to_string(),
+        "SELECT * FROM users u WHERE u.active = $1 \
+         AND EXISTS (SELECT 1 FROM orders o WHERE o.user_id = u.id AND o.total > $2) \
+         AND NOT EXISTS (SELECT 1 FROM refunds r WHERE r.user_id = u.id AND r.reason = $3) \
+         AND u.country = $4"
     );
+    assert_eq!(query.into_args().len(), 4);
 }
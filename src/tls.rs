@@ -0,0 +1,203 @@
+use std::{
+    error::Error,
+    future::Future,
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+#[cfg(feature = "native-tls")]
+use postgres_native_tls::MakeTlsConnector;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_postgres::{
+    tls::{self, ChannelBinding, MakeTlsConnect, NoTlsStream, TlsConnect},
+    NoTls, Socket,
+};
+#[cfg(feature = "rustls")]
+use tokio_postgres_rustls::MakeRustlsConnect;
+
+use crate::DatabaseError;
+
+type BoxError = Box<dyn Error + Send + Sync>;
+
+/// TLS implementation used by `Database::connect`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TlsMode {
+    NoTls,
+
+    /// TLS through the platform's native library, using the system's trusted
+    /// certificates. Requires the `native-tls` feature.
+    #[cfg(feature = "native-tls")]
+    Native,
+
+    /// TLS through rustls, trusting the Mozilla root certificates. Requires the
+    /// `rustls` feature.
+    #[cfg(feature = "rustls")]
+    Rustls,
+}
+
+/// A `MakeTlsConnect` implementation that picks the TLS implementation at
+/// runtime according to a `TlsMode`.
+///
+/// Unlike `NoTls` itself, this type cannot tell tokio-postgres up front that it
+/// does not support TLS, so `Database::connect` disables `sslmode=prefer` for
+/// `TlsMode::NoTls` instead.
+#[derive(Clone)]
+pub struct MakeTls(MakeTlsInner);
+
+#[derive(Clone)]
+enum MakeTlsInner {
+    NoTls(NoTls),
+    #[cfg(feature = "native-tls")]
+    Native(MakeTlsConnector),
+    #[cfg(feature = "rustls")]
+    Rustls(MakeRustlsConnect),
+}
+
+impl MakeTls {
+    pub(crate) fn new(mode: TlsMode) -> Result<Self, DatabaseError> {
+        let inner = match mode {
+            TlsMode::NoTls => MakeTlsInner::NoTls(NoTls),
+            #[cfg(feature = "native-tls")]
+            TlsMode::Native => {
+                let connector = native_tls::TlsConnector::new()
+                    .map_err(|e| DatabaseError::TlsError(e.into()))?;
+
+                MakeTlsInner::Native(MakeTlsConnector::new(connector))
+            }
+            #[cfg(feature = "rustls")]
+            TlsMode::Rustls => {
+                let roots = rustls::RootCertStore {
+                    roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+                };
+
+                let config = rustls::ClientConfig::builder_with_provider(
+                    rustls::crypto::ring::default_provider().into(),
+                )
+                .with_safe_default_protocol_versions()
+                .map_err(|e| DatabaseError::TlsError(e.into()))?
+                .with_root_certificates(roots)
+                .with_no_client_auth();
+
+                MakeTlsInner::Rustls(MakeRustlsConnect::new(config))
+            }
+        };
+
+        Ok(Self(inner))
+    }
+}
+
+impl MakeTlsConnect<Socket> for MakeTls {
+    type Stream = TlsStream;
+    type TlsConnect = TlsConnector;
+    type Error = BoxError;
+
+    fn make_tls_connect(&mut self, domain: &str) -> Result<TlsConnector, BoxError> {
+        Ok(match &mut self.0 {
+            MakeTlsInner::NoTls(t) => {
+                TlsConnector::NoTls(MakeTlsConnect::<Socket>::make_tls_connect(t, domain)?)
+            }
+            #[cfg(feature = "native-tls")]
+            MakeTlsInner::Native(t) => {
+                TlsConnector::Native(MakeTlsConnect::<Socket>::make_tls_connect(t, domain)?)
+            }
+            #[cfg(feature = "rustls")]
+            MakeTlsInner::Rustls(t) => {
+                TlsConnector::Rustls(MakeTlsConnect::<Socket>::make_tls_connect(t, domain)?)
+            }
+        })
+    }
+}
+
+pub enum TlsConnector {
+    NoTls(NoTls),
+    #[cfg(feature = "native-tls")]
+    Native(<MakeTlsConnector as MakeTlsConnect<Socket>>::TlsConnect),
+    #[cfg(feature = "rustls")]
+    Rustls(<MakeRustlsConnect as MakeTlsConnect<Socket>>::TlsConnect),
+}
+
+impl TlsConnect<Socket> for TlsConnector {
+    type Stream = TlsStream;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<TlsStream, BoxError>> + Send>>;
+
+    fn connect(self, stream: Socket) -> Self::Future {
+        match self {
+            TlsConnector::NoTls(t) => {
+                let f = t.connect(stream);
+                Box::pin(async move { Ok(TlsStream::NoTls(f.await?)) })
+            }
+            #[cfg(feature = "native-tls")]
+            TlsConnector::Native(t) => {
+                let f = t.connect(stream);
+                Box::pin(async move { Ok(TlsStream::Native(f.await?)) })
+            }
+            #[cfg(feature = "rustls")]
+            TlsConnector::Rustls(t) => {
+                let f = t.connect(stream);
+                Box::pin(async move { Ok(TlsStream::Rustls(Box::new(f.await?))) })
+            }
+        }
+    }
+}
+
+pub enum TlsStream {
+    NoTls(NoTlsStream),
+    #[cfg(feature = "native-tls")]
+    Native(<MakeTlsConnector as MakeTlsConnect<Socket>>::Stream),
+    #[cfg(feature = "rustls")]
+    Rustls(Box<<MakeRustlsConnect as MakeTlsConnect<Socket>>::Stream>),
+}
+
+macro_rules! dispatch {
+    ($self: expr, $s: ident => $e: expr) => {
+        match $self.get_mut() {
+            TlsStream::NoTls($s) => $e,
+            #[cfg(feature = "native-tls")]
+            TlsStream::Native($s) => $e,
+            #[cfg(feature = "rustls")]
+            TlsStream::Rustls($s) => $e,
+        }
+    };
+}
+
+impl AsyncRead for TlsStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        dispatch!(self, s => Pin::new(s).poll_read(cx, buf))
+    }
+}
+
+impl AsyncWrite for TlsStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        dispatch!(self, s => Pin::new(s).poll_write(cx, buf))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        dispatch!(self, s => Pin::new(s).poll_flush(cx))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        dispatch!(self, s => Pin::new(s).poll_shutdown(cx))
+    }
+}
+
+impl tls::TlsStream for TlsStream {
+    fn channel_binding(&self) -> ChannelBinding {
+        match self {
+            TlsStream::NoTls(s) => s.channel_binding(),
+            #[cfg(feature = "native-tls")]
+            TlsStream::Native(s) => s.channel_binding(),
+            #[cfg(feature = "rustls")]
+            TlsStream::Rustls(s) => s.channel_binding(),
+        }
+    }
+}
@@ -0,0 +1,10 @@
+//! Re-exports of third-party types with `ToSql`/`FromSql` support, in the
+//! versions tokio-postgres implements them for. Binding a `Uuid` from a
+//! different major version of the `uuid` crate fails to compile, which these
+//! re-exports avoid. They bind like any other value, e.g.
+//! `("id = ?", Uuid::nil())`.
+
+#[cfg(feature = "with-chrono-0_4")]
+pub use chrono::{DateTime, Utc};
+#[cfg(feature = "with-uuid-1")]
+pub use uuid::Uuid;
@@ -1,4 +1,35 @@
-use pgutils::migrate::Migrator;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use pgutils::migrate::{ChecksumAlgo, Migration, MigrationError, Migrator, StatementDelimiter};
+use tokio_postgres::{Client, NoTls};
+
+/// Connects to the database in `PGUTILS_TEST_DATABASE_URL` and creates a
+/// uniquely named schema for the test. Returns `None` if the variable is not
+/// set, in which case tests that need a database are skipped.
+async fn test_db() -> Option<(Client, String)> {
+    let url = std::env::var("PGUTILS_TEST_DATABASE_URL").ok()?;
+    let (db, connection) = tokio_postgres::connect(&url, NoTls).await.unwrap();
+    tokio::spawn(connection);
+
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+    let schema = format!("pgutils_test_{}_{}", std::process::id(), nanos);
+
+    db.batch_execute(&format!("CREATE SCHEMA {}", schema))
+        .await
+        .unwrap();
+
+    Some((db, schema))
+}
+
+async fn drop_schema(db: &Client, schema: &str) {
+    db.batch_execute(&format!("DROP SCHEMA {} CASCADE", schema))
+        .await
+        .unwrap();
+}
 
 #[test]
 fn test_simple_load() {
@@ -14,3 +45,495 @@ fn test_simple_load() {
         m.migrations[0].checksum
     );
 }
+
+#[test]
+fn test_normalized_checksum() {
+    let algo = ChecksumAlgo::Sha256Normalized;
+
+    assert_eq!(
+        algo.checksum("SELECT 1 AS one;"),
+        algo.checksum("SELECT 1 AS one;   \n\n")
+    );
+
+    assert_ne!(
+        ChecksumAlgo::Sha256.checksum("SELECT 1 AS one;"),
+        ChecksumAlgo::Sha256.checksum("SELECT 1 AS one;   \n\n")
+    );
+}
+
+#[test]
+fn test_unordered_checksum() {
+    let algo = ChecksumAlgo::Sha256Unordered;
+    let sql = "CREATE INDEX a_idx ON t (a);\nCREATE INDEX b_idx ON t (b);";
+    let reordered = "CREATE INDEX b_idx ON t (b);\n\n  CREATE INDEX a_idx ON t (a);\n";
+
+    assert_eq!(algo.checksum(sql), algo.checksum(reordered));
+    assert_ne!(
+        algo.checksum(sql),
+        algo.checksum("CREATE INDEX a_idx ON t (a, b);\nCREATE INDEX b_idx ON t (b);")
+    );
+    assert_ne!(
+        ChecksumAlgo::Sha256.checksum(sql),
+        ChecksumAlgo::Sha256.checksum(reordered)
+    );
+
+    assert!(matches!(
+        "sha256-unordered".parse(),
+        Ok(ChecksumAlgo::Sha256Unordered)
+    ));
+}
+
+#[test]
+fn test_line_endings_do_not_affect_checksum() {
+    let lf = Migration::new(1, "lf".into(), "SELECT 1;\nSELECT 2;\n".into());
+    let crlf = Migration::new(1, "crlf".into(), "SELECT 1;\r\nSELECT 2;\r\n".into());
+
+    assert_eq!(lf.checksum, crlf.checksum);
+    assert_eq!(lf.sql, crlf.sql);
+}
+
+#[test]
+fn test_parent_dir_load() {
+    let m: Migrator = pgutils::migrate::embed!("tests/stubs/../stubs/simple");
+
+    assert_eq!(1, m.migrations.len());
+    assert_eq!(1614877844, m.migrations[0].version);
+}
+
+#[test]
+fn test_statements() {
+    let m = Migration::new(
+        1,
+        "statements".into(),
+        r#"
+            CREATE TABLE a (b TEXT DEFAULT ';');
+            -- comment; with semicolon
+            CREATE FUNCTION f() RETURNS INT AS $body$
+                BEGIN RETURN 1; END;
+            $body$ LANGUAGE plpgsql;
+            /* another; comment */ SELECT $1, "x;y" FROM a;
+            ;
+        "#
+        .into(),
+    );
+
+    let statements = m.statements();
+
+    assert_eq!(3, statements.len());
+    assert_eq!("CREATE TABLE a (b TEXT DEFAULT ';')", statements[0]);
+    assert!(statements[1].starts_with("-- comment; with semicolon"));
+    assert!(statements[1].ends_with("$body$ LANGUAGE plpgsql"));
+    assert_eq!(
+        r#"/* another; comment */ SELECT $1, "x;y" FROM a"#,
+        statements[2]
+    );
+}
+
+#[test]
+fn test_from_sources() {
+    let m = Migrator::from_sources(vec![
+        (2, "second".into(), "SELECT 2;".into()),
+        (1, "first".into(), "SELECT 1;".into()),
+    ])
+    .unwrap();
+
+    assert_eq!(2, m.migrations.len());
+    assert_eq!("first", m.migrations[0].name);
+    assert_eq!(2, m.migrations[1].version);
+
+    assert!(matches!(
+        Migrator::from_sources(vec![(1, "Invalid-Name".into(), "SELECT 1;".into())]),
+        Err(MigrationError::NameError(_))
+    ));
+
+    assert!(matches!(
+        Migrator::from_sources(vec![
+            (1, "first".into(), "SELECT 1;".into()),
+            (1, "again".into(), "SELECT 1;".into()),
+        ]),
+        Err(MigrationError::DuplicateVersion(1))
+    ));
+}
+
+#[test]
+fn test_version_gaps() {
+    let m = Migrator::from_sources(vec![
+        (1, "first".into(), "SELECT 1;".into()),
+        (2, "second".into(), "SELECT 2;".into()),
+        (4, "fourth".into(), "SELECT 4;".into()),
+    ])
+    .unwrap();
+
+    assert!(m.validate().is_ok());
+
+    assert!(matches!(
+        m.reject_version_gaps(true).validate(),
+        Err(MigrationError::VersionGap { missing: 3 })
+    ));
+}
+
+#[test]
+fn test_duplicate_versions() {
+    let m = Migrator::new(vec![
+        Migration::new(1, "first".into(), "SELECT 1;".into()),
+        Migration::new(1, "again".into(), "SELECT 1;".into()),
+    ]);
+
+    assert!(matches!(
+        m.validate(),
+        Err(MigrationError::DuplicateVersion(1))
+    ));
+}
+
+#[test]
+fn test_labels() {
+    let m = Migration::new(
+        1,
+        "drop_users".into(),
+        "-- Removes legacy users.\n-- pgutils: labels=destructive, slow\n\nDROP TABLE users;\n-- pgutils: labels=ignored"
+            .into(),
+    );
+
+    assert_eq!(vec!["destructive", "slow"], m.labels);
+    assert!(m.transactional);
+
+    let m = Migration::new(2, "plain".into(), "SELECT 1;".into());
+    assert!(m.labels.is_empty());
+}
+
+#[test]
+fn test_go_batches() {
+    let m = Migration::new(
+        1,
+        "batches".into(),
+        "CREATE TABLE a (id INT);\nINSERT INTO a VALUES (1);\nGO\n\nSELECT 'GO';\n  go  \nGO\nSELECT 2"
+            .into(),
+    );
+
+    assert_eq!(
+        vec![
+            "CREATE TABLE a (id INT);\nINSERT INTO a VALUES (1);",
+            "SELECT 'GO';",
+            "SELECT 2"
+        ],
+        m.statements_with(StatementDelimiter::GoBatch)
+    );
+    assert_eq!(4, m.statements_with(StatementDelimiter::Semicolon).len());
+}
+
+#[cfg(feature = "migrate-recorder")]
+#[tokio::test]
+async fn test_recorder() {
+    let m = Migrator::from_sources(vec![
+        (
+            1,
+            "first".into(),
+            "CREATE TABLE a (id INT); CREATE TABLE b (id INT);".into(),
+        ),
+        (2, "second".into(), "DROP TABLE b".into()),
+    ])
+    .unwrap();
+
+    let mut recorder = pgutils::migrate::Recorder::new();
+    m.apply_to(&mut recorder).await.unwrap();
+
+    assert_eq!(
+        vec![
+            "CREATE TABLE a (id INT)",
+            "CREATE TABLE b (id INT)",
+            "DROP TABLE b"
+        ],
+        recorder.statements()
+    );
+}
+
+#[test]
+fn test_from_dir() {
+    let m = Migrator::from_dir(Path::new("tests/stubs/simple")).unwrap();
+
+    assert_eq!(1, m.migrations.len());
+    assert_eq!("simple_migration", m.migrations[0].name);
+    assert_eq!(1614877844, m.migrations[0].version);
+
+    assert!(matches!(
+        Migrator::from_dir(Path::new("tests/stubs/missing")),
+        Err(MigrationError::IOError(_))
+    ));
+}
+
+#[test]
+fn test_display_id() {
+    let m = Migration::new(7, "add_users".into(), "SELECT 1;".into());
+    assert_eq!("0007_add_users", m.display_id());
+
+    let m = Migration::new(1614877844, "simple".into(), "SELECT 1;".into());
+    assert_eq!("1614877844_simple", m.display_id());
+}
+
+#[test]
+fn test_no_transaction() {
+    let m = Migration::new(
+        1,
+        "add_index".into(),
+        "-- pgutils: no-transaction\n-- pgutils: labels=slow\nCREATE INDEX CONCURRENTLY a_idx ON a (id);"
+            .into(),
+    );
+
+    assert!(!m.transactional);
+    assert_eq!(vec!["slow"], m.labels);
+}
+
+#[test]
+fn test_analyze_after_names() {
+    let m = Migrator::new(vec![]).analyze_after(vec!["public.users".into()]);
+    assert!(m.validate().is_ok());
+
+    let m = Migrator::new(vec![]).analyze_after(vec!["users; DROP TABLE users".into()]);
+    assert!(matches!(
+        m.validate(),
+        Err(MigrationError::TableNameError(_))
+    ));
+}
+
+#[test]
+fn test_templated_load() {
+    let m: Migrator = pgutils::migrate::embed!("tests/stubs/templated");
+    let sql = "CREATE TABLE pgutils.users (id INT);\nSELECT $1, '${not a var}', '${ESCAPED}';\n";
+
+    assert_eq!(sql, m.migrations[0].sql);
+    assert_eq!(
+        Migration::new(1, "templated".into(), sql.into()).checksum,
+        m.migrations[0].checksum
+    );
+
+    let loaded = Migrator::from_dir(Path::new("tests/stubs/templated")).unwrap();
+    assert_eq!(sql, loaded.migrations[0].sql);
+    assert_eq!(m.migrations[0].checksum, loaded.migrations[0].checksum);
+}
+
+#[test]
+fn test_single_transaction() {
+    let m = Migrator::from_sources(vec![
+        (1, "create".into(), "CREATE TABLE a (id INT);".into()),
+        (
+            2,
+            "index".into(),
+            "-- pgutils: no-transaction\nCREATE INDEX CONCURRENTLY a_idx ON a (id);".into(),
+        ),
+    ])
+    .unwrap();
+
+    assert!(m.validate().is_ok());
+    assert!(matches!(
+        m.single_transaction().validate(),
+        Err(MigrationError::NotTransactional(2))
+    ));
+}
+
+#[tokio::test]
+async fn test_edited_no_transaction_migration() {
+    let Some((mut db, schema)) = test_db().await else {
+        return;
+    };
+
+    let migrator = |sql: &str| {
+        Migrator::from_sources(vec![(
+            1,
+            "tables".into(),
+            format!(
+                "-- pgutils: no-transaction\n{}",
+                sql.replace("{s}", &schema)
+            ),
+        )])
+        .unwrap()
+        .table_name(&format!("{}.migrations", schema))
+    };
+
+    let failing = migrator("CREATE TABLE {s}.a (id INT);\nSELECT 1 / 0;");
+    assert!(matches!(
+        failing.migrate(&mut db).await,
+        Err(MigrationError::ApplyError {
+            version: 1,
+            statement_index: 2,
+            ..
+        })
+    ));
+
+    let fixed = migrator("CREATE TABLE {s}.b (id INT);\nCREATE TABLE {s}.c (id INT);");
+    fixed.migrate(&mut db).await.unwrap();
+
+    db.batch_execute(&format!("SELECT FROM {s}.b, {s}.c", s = schema))
+        .await
+        .unwrap();
+
+    drop_schema(&db, &schema).await;
+}
+
+#[tokio::test]
+async fn test_table_lock() {
+    let Some((mut db, schema)) = test_db().await else {
+        return;
+    };
+
+    let migrator = |sources: Vec<(i64, String, String)>| {
+        Migrator::from_sources(sources)
+            .unwrap()
+            .table_name(&format!("{}.migrations", schema))
+            .table_lock(Duration::from_secs(60))
+    };
+
+    let first = (1, "first".to_owned(), "SELECT 1;".to_owned());
+    let second = (2, "second".to_owned(), "SELECT 2;".to_owned());
+
+    migrator(vec![first.clone()])
+        .migrate(&mut db)
+        .await
+        .unwrap();
+
+    let lock = format!("{}.migrations_lock", schema);
+    db.execute(
+        &format!("INSERT INTO {} (holder) VALUES ('other')", lock),
+        &[],
+    )
+    .await
+    .unwrap();
+
+    let pending = migrator(vec![first, second]);
+    assert!(matches!(
+        pending.migrate(&mut db).await,
+        Err(MigrationError::Locked)
+    ));
+
+    db.execute(
+        &format!("UPDATE {} SET locked_at = now() - interval '1 hour'", lock),
+        &[],
+    )
+    .await
+    .unwrap();
+
+    pending.migrate(&mut db).await.unwrap();
+
+    let row = db
+        .query_one(&format!("SELECT count(*) FROM {}", lock), &[])
+        .await
+        .unwrap();
+    assert_eq!(0, row.get::<_, i64>(0));
+
+    drop_schema(&db, &schema).await;
+}
+
+#[tokio::test]
+async fn test_table_lock_lost() {
+    let Some((mut db, schema)) = test_db().await else {
+        return;
+    };
+
+    let m = Migrator::from_sources(vec![
+        (
+            1,
+            "take_over".into(),
+            format!("UPDATE {}.migrations_lock SET holder = 'other';", schema),
+        ),
+        (2, "second".into(), "SELECT 1;".into()),
+    ])
+    .unwrap()
+    .table_name(&format!("{}.migrations", schema))
+    .table_lock(Duration::from_secs(60));
+
+    assert!(matches!(
+        m.migrate(&mut db).await,
+        Err(MigrationError::LockLost)
+    ));
+
+    drop_schema(&db, &schema).await;
+}
+
+#[tokio::test]
+async fn test_migrate_in_tx_rejects_no_transaction() {
+    let Some((mut db, schema)) = test_db().await else {
+        return;
+    };
+
+    let m = Migrator::from_sources(vec![
+        (
+            1,
+            "create".into(),
+            format!("CREATE TABLE {}.a (id INT);", schema),
+        ),
+        (
+            2,
+            "index".into(),
+            format!(
+                "-- pgutils: no-transaction\nCREATE INDEX CONCURRENTLY a_idx ON {}.a (id);",
+                schema
+            ),
+        ),
+    ])
+    .unwrap()
+    .table_name(&format!("{}.migrations", schema));
+
+    let tx = db.transaction().await.unwrap();
+    assert!(matches!(
+        m.migrate_in_tx(&tx).await,
+        Err(MigrationError::NotTransactional(2))
+    ));
+    tx.rollback().await.unwrap();
+
+    drop_schema(&db, &schema).await;
+}
+
+#[tokio::test]
+async fn test_is_up_to_date() {
+    let Some((mut db, schema)) = test_db().await else {
+        return;
+    };
+
+    let migrator = |versions: &[i64]| {
+        let sources = versions
+            .iter()
+            .map(|v| (*v, "select".into(), "SELECT 1;".into()))
+            .collect();
+
+        Migrator::from_sources(sources)
+            .unwrap()
+            .table_name(&format!("{}.migrations", schema))
+    };
+
+    assert!(!migrator(&[1, 2, 5]).is_up_to_date(&db).await.unwrap());
+
+    migrator(&[1, 2, 5]).migrate(&mut db).await.unwrap();
+
+    assert!(migrator(&[1, 2, 5]).is_up_to_date(&db).await.unwrap());
+    assert!(!migrator(&[1, 3, 5]).is_up_to_date(&db).await.unwrap());
+    assert!(!migrator(&[1, 2, 5, 6]).is_up_to_date(&db).await.unwrap());
+
+    drop_schema(&db, &schema).await;
+}
+
+#[tokio::test]
+async fn test_no_transaction_statement_timeout() {
+    let Some((mut db, schema)) = test_db().await else {
+        return;
+    };
+
+    let m = Migrator::from_sources(vec![(
+        1,
+        "check".into(),
+        "-- pgutils: no-transaction\nDO $$ BEGIN ASSERT current_setting('statement_timeout') = '1s'; END $$;"
+            .into(),
+    )])
+    .unwrap()
+    .table_name(&format!("{}.migrations", schema))
+    .statement_timeout(Duration::from_secs(1));
+
+    db.batch_execute("SET statement_timeout = '7s'")
+        .await
+        .unwrap();
+    m.migrate(&mut db).await.unwrap();
+
+    let row = db.query_one("SHOW statement_timeout", &[]).await.unwrap();
+    assert_eq!("7s", row.get::<_, &str>(0));
+
+    db.batch_execute("RESET statement_timeout").await.unwrap();
+    drop_schema(&db, &schema).await;
+}
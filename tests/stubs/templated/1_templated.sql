@@ -0,0 +1,2 @@
+CREATE TABLE ${CARGO_PKG_NAME}.users (id INT);
+SELECT $1, '${not a var}', '$${ESCAPED}';